@@ -0,0 +1,212 @@
+use crate::{endpoint, health, route::Upstream, Backend, SharedState, State};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, net::SocketAddr};
+use tokio::{net::TcpListener, sync::watch};
+
+/// how `forward` may come to pick a backend
+#[derive(Default)]
+struct Uses<'a> {
+    /// for backends health checks can concern
+    addr: Option<SocketAddr>,
+    routes: Vec<&'a str>,
+    canary_for: Vec<&'a str>,
+    content_types: Vec<&'a str>,
+}
+
+/// every backend requests can go to, from the routes, canaries and content
+/// routes in effect, with their last health check and whether their circuit
+/// is open, and the backend everything else goes to
+fn backends(state: &State) -> Value {
+    let mut backends = BTreeMap::<String, Uses>::new();
+    for (subdomain, (upstream, _)) in &state.routes {
+        let targets: Vec<(Backend, Option<SocketAddr>)> = match upstream {
+            Upstream::Balancer(balancer) => balancer
+                .addrs()
+                .map(|addr| (Backend::Addr(addr), Some(addr)))
+                .collect(),
+            #[cfg(unix)]
+            Upstream::Unix(path) => vec![(Backend::Unix(path.clone()), None)],
+        };
+        for (backend, addr) in targets {
+            let uses = backends.entry(backend.to_string()).or_default();
+            uses.addr = addr;
+            uses.routes.push(subdomain);
+        }
+    }
+    for (subdomain, &(addr, _)) in &state.canaries {
+        let uses = backends.entry(addr.to_string()).or_default();
+        uses.addr = Some(addr);
+        uses.canary_for.push(subdomain);
+    }
+    for (media_type, backend) in &state.content_routes {
+        let uses = backends.entry(backend.to_string()).or_default();
+        if let Backend::Addr(addr) = backend {
+            uses.addr = Some(*addr);
+        }
+        uses.content_types.push(media_type);
+    }
+    let backends = backends
+        .into_iter()
+        .map(|(address, mut uses)| {
+            uses.routes.sort_unstable();
+            uses.canary_for.sort_unstable();
+            json!({
+                "routes": uses.routes,
+                "canary_for": uses.canary_for,
+                "content_types": uses.content_types,
+                "up": uses.addr.is_none_or(|addr| health::is_up(&state.health, addr)),
+                "circuit_open": state
+                    .breaker
+                    .as_ref()
+                    .is_some_and(|breaker| breaker.is_open(&address)),
+                "address": address,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "default": format!("{}:{}", state.args.backend_host, state.args.backend_port),
+        "backends": backends,
+    })
+}
+
+/// the arguments in effect, as in the config file, with the basic auth
+/// passwords and the values of added request headers left out
+fn config(state: &State) -> Value {
+    let mut config = serde_json::to_value(&state.args).expect("arguments serialize");
+    if let Some(credentials) = config.get_mut("basic-auth").and_then(Value::as_array_mut) {
+        for entry in credentials {
+            if let Some((user, _)) = entry.as_str().and_then(|entry| entry.split_once(':')) {
+                *entry = format!("{}:<redacted>", user).into();
+            }
+        }
+    }
+    if let Some(headers) = config
+        .get_mut("add-request-header")
+        .and_then(Value::as_array_mut)
+    {
+        for entry in headers {
+            if let Some((name, _)) = entry.as_str().and_then(|entry| entry.split_once(':')) {
+                *entry = format!("{}:<redacted>", name).into();
+            }
+        }
+    }
+    config
+}
+
+fn connections(state: &State) -> Value {
+    json!({ "open": state.metrics.open_connections() })
+}
+
+/// serve `/admin/backends`, `/admin/config` and `/admin/connections` from the
+/// current state until shutdown is requested
+pub async fn serve(listener: TcpListener, shared: SharedState, shutdown: watch::Receiver<bool>) {
+    let respond = move |path: &str| {
+        let state = shared.current();
+        let body = match path {
+            "/admin/backends" => backends(&state),
+            "/admin/config" => config(&state),
+            "/admin/connections" => connections(&state),
+            _ => return None,
+        };
+        Some(("application/json", body.to_string()))
+    };
+    endpoint::serve(listener, "admin", respond, shutdown).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Args;
+    use clap::Parser as _;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpStream;
+
+    async fn get(addr: SocketAddr, path: &str) -> (String, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    path
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let body = serde_json::from_str(body).unwrap_or(Value::Null);
+        (head.to_string(), body)
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let mut args = vec![
+            "http-proxy",
+            "--backend-port",
+            "3000",
+            "--route",
+            "api=127.0.0.1:3001,127.0.0.1:3002",
+            "--route",
+            "web=127.0.0.1:3002",
+            "--canary",
+            "web=127.0.0.1:3003@10%",
+            "--content-route",
+            "application/grpc=127.0.0.1:3001",
+            "--basic-auth",
+            "alice:secret",
+            "--add-request-header",
+            "x-token:abc",
+            "--breaker-threshold",
+            "1",
+        ];
+        if cfg!(unix) {
+            args.extend(["--route", "app=unix:/run/app.sock"]);
+        }
+        let args = Args::parse_from(args);
+        let state = State::new(args).unwrap();
+        state.breaker.as_ref().unwrap().failure("127.0.0.1:3002");
+        let shared = SharedState::new(state);
+        let _open = shared.current().metrics.connection_opened();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve(listener, shared, shutdown_rx));
+
+        let (head, backends) = get(addr, "/admin/backends").await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("content-type: application/json"), "{}", head);
+        let mut expected = vec![
+            json!({"address": "127.0.0.1:3001", "routes": ["api"], "canary_for": [], "content_types": ["application/grpc"], "up": true, "circuit_open": false}),
+            json!({"address": "127.0.0.1:3002", "routes": ["api", "web"], "canary_for": [], "content_types": [], "up": true, "circuit_open": true}),
+            json!({"address": "127.0.0.1:3003", "routes": [], "canary_for": ["web"], "content_types": [], "up": true, "circuit_open": false}),
+        ];
+        if cfg!(unix) {
+            expected.push(json!({"address": "unix:/run/app.sock", "routes": ["app"], "canary_for": [], "content_types": [], "up": true, "circuit_open": false}));
+        }
+        assert_eq!(
+            backends,
+            json!({
+                "default": "localhost:3000",
+                "backends": expected,
+            })
+        );
+
+        let (_, config) = get(addr, "/admin/config").await;
+        assert_eq!(config["backend-port"], 3000);
+        assert_eq!(config["basic-auth"], json!(["alice:<redacted>"]));
+        assert_eq!(config["add-request-header"], json!(["x-token:<redacted>"]));
+        assert_eq!(config["route"][1], "web=127.0.0.1:3002");
+
+        let (_, connections) = get(addr, "/admin/connections").await;
+        assert_eq!(connections, json!({ "open": 1 }));
+
+        let (head, _) = get(addr, "/admin/nothing").await;
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", head);
+
+        shutdown_tx.send(true).unwrap();
+        server.await.unwrap();
+    }
+}
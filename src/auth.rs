@@ -0,0 +1,81 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hyper::{header::AUTHORIZATION, HeaderMap};
+
+/// HTTP basic auth against a fixed set of `user:password` credentials
+pub struct BasicAuth {
+    credentials: Vec<Vec<u8>>,
+}
+
+impl BasicAuth {
+    pub fn new(credentials: &[String]) -> Self {
+        Self {
+            credentials: credentials.iter().map(|c| c.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    /// whether the `authorization` header carries one of the credentials
+    pub fn check(&self, headers: &HeaderMap) -> bool {
+        let Some(decoded) = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                let (scheme, token) = value.split_once(' ')?;
+                scheme.eq_ignore_ascii_case("basic").then_some(token.trim())
+            })
+            .and_then(|token| STANDARD.decode(token).ok())
+        else {
+            return false;
+        };
+        // every credential is compared so the time taken does not tell which
+        // one came close
+        self.credentials.iter().fold(false, |found, credential| {
+            found | constant_time_eq(credential, &decoded)
+        })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub fn parse_credentials(s: &str) -> Result<String, String> {
+    match s.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(s.to_string()),
+        _ => Err(format!("expected <user>:<password>, got {:?}", s)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let auth = BasicAuth::new(&["alice:secret".to_string(), "bob:hunter2".to_string()]);
+        let mut headers = HeaderMap::new();
+        assert!(!auth.check(&headers));
+
+        let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+        headers.insert(AUTHORIZATION, basic("bob:hunter2").parse().unwrap());
+        assert!(auth.check(&headers));
+        headers.insert(AUTHORIZATION, basic("bob:secret").parse().unwrap());
+        assert!(!auth.check(&headers));
+        headers.insert(AUTHORIZATION, basic("alice:secret!").parse().unwrap());
+        assert!(!auth.check(&headers));
+        headers.insert(AUTHORIZATION, "Bearer alice:secret".parse().unwrap());
+        assert!(!auth.check(&headers));
+        headers.insert(AUTHORIZATION, "basic not base64".parse().unwrap());
+        assert!(!auth.check(&headers));
+    }
+
+    #[test]
+    fn test_parse_credentials() {
+        assert!(parse_credentials("alice:secret").is_ok());
+        assert!(parse_credentials("alice:").is_ok());
+        assert!(parse_credentials("alice").is_err());
+        assert!(parse_credentials(":secret").is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use crate::BoxError;
+use brotli::CompressorWriter;
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    HeaderMap,
+};
+use pin_project_lite::pin_project;
+use std::{
+    future::Future,
+    io::{self, Write},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::time::{Instant, Sleep};
+
+pin_project! {
+    /// body that fails with a timeout error once `deadline` has passed
+    pub struct Deadline<B> {
+        #[pin]
+        inner: B,
+        #[pin]
+        sleep: Sleep,
+    }
+}
+
+impl<B> Deadline<B> {
+    pub fn new(inner: B, deadline: Instant) -> Self {
+        Self {
+            inner,
+            sleep: tokio::time::sleep_until(deadline),
+        }
+    }
+}
+
+impl<B> Body for Deadline<B>
+where
+    B: Body,
+    B::Error: Into<BoxError>,
+{
+    type Data = B::Data;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        if let Poll::Ready(frame) = this.inner.poll_frame(cx) {
+            return Poll::Ready(frame.map(|frame| frame.map_err(Into::into)));
+        }
+        match this.sleep.poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "request timed out while streaming the response",
+            )
+            .into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pin_project! {
+    /// body that gzip- or brotli-compresses the data frames of `inner` as
+    /// they arrive
+    pub struct Compress<B> {
+        #[pin]
+        inner: B,
+        encoder: Option<Encoder>,
+        trailers: Option<HeaderMap>,
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Brotli(encoder) => encoder,
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+/// brotli's default window of 4 MiB
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+impl<B> Compress<B> {
+    /// gzip at `level` from 1 (fastest) to 9 (smallest), 6 by default
+    pub fn gzip(inner: B, level: Option<u32>) -> Self {
+        let level = level.map_or_else(Compression::default, Compression::new);
+        Self::new(inner, Encoder::Gzip(GzEncoder::new(Vec::new(), level)))
+    }
+
+    /// brotli at `quality` from 0 (fastest) to 11 (smallest), 5 by default
+    /// as the higher ones are too slow for responses on the fly
+    pub fn brotli(inner: B, quality: Option<u32>) -> Self {
+        let encoder =
+            CompressorWriter::new(Vec::new(), 4096, quality.unwrap_or(5), BROTLI_WINDOW_BITS);
+        Self::new(inner, Encoder::Brotli(Box::new(encoder)))
+    }
+
+    fn new(inner: B, encoder: Encoder) -> Self {
+        Self {
+            inner,
+            encoder: Some(encoder),
+            trailers: None,
+        }
+    }
+}
+
+impl<B> Body for Compress<B>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(this.trailers.take().map(|t| Ok(Frame::trailers(t))));
+            };
+            match ready!(this.inner.as_mut().poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => {
+                        // flush after every chunk so streamed responses are
+                        // not held back until the encoder's buffer fills up
+                        encoder.writer().write_all(&data)?;
+                        encoder.writer().flush()?;
+                        let compressed = std::mem::take(encoder.output());
+                        if !compressed.is_empty() {
+                            return Poll::Ready(Some(Ok(Frame::data(compressed.into()))));
+                        }
+                    }
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            *this.trailers = Some(trailers);
+                        }
+                    }
+                },
+                Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+                None => {
+                    let compressed = this.encoder.take().unwrap().finish()?;
+                    return Poll::Ready(Some(Ok(Frame::data(compressed.into()))));
+                }
+            }
+        }
+    }
+}
+
+/// called once a body is through, or dropped before that, with how long it
+/// waited for `inner` and for its reader respectively
+pub type OnDone = Box<dyn FnOnce(Duration, Duration) + Send + Sync>;
+
+pin_project! {
+    /// body that keeps apart the time spent waiting for frames of `inner`,
+    /// e.g. from a slow backend, from the time between handing a frame over
+    /// and being asked for the next one, e.g. for a slow client
+    pub struct Timed<B> {
+        #[pin]
+        inner: B,
+        waits: Waits,
+    }
+}
+
+struct Waits {
+    inner: Duration,
+    reader: Duration,
+    /// start of the pending poll for the next frame
+    polling_since: Option<Instant>,
+    /// when the last frame was handed over
+    handed_over: Option<Instant>,
+    on_done: Option<OnDone>,
+}
+
+impl Waits {
+    fn done(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(self.inner, self.reader);
+        }
+    }
+}
+
+impl Drop for Waits {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+impl<B> Timed<B> {
+    pub fn new(inner: B, on_done: OnDone) -> Self {
+        Self {
+            inner,
+            waits: Waits {
+                inner: Duration::ZERO,
+                reader: Duration::ZERO,
+                polling_since: None,
+                handed_over: None,
+                on_done: Some(on_done),
+            },
+        }
+    }
+}
+
+impl<B: Body> Body for Timed<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let waits = this.waits;
+        let now = Instant::now();
+        if let Some(handed_over) = waits.handed_over.take() {
+            waits.reader += now - handed_over;
+        }
+        let polling_since = *waits.polling_since.get_or_insert(now);
+        let frame = ready!(this.inner.poll_frame(cx));
+        let now = Instant::now();
+        waits.inner += now - polling_since;
+        waits.polling_since = None;
+        match &frame {
+            Some(Ok(_)) => waits.handed_over = Some(now),
+            Some(Err(_)) | None => waits.done(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
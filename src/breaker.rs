@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// per backend circuit breaker: after `threshold` consecutive connect
+/// failures the circuit opens and requests fail fast until `cooldown` has
+/// passed, after which a single probe decides whether it closes again
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Circuit {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// a probe is in flight; should it never report back, another one is
+    /// let through after the next cooldown
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// whether a connection attempt to `backend` may be made
+    pub fn allow(&self, backend: &str) -> bool {
+        self.allow_at(backend, Instant::now())
+    }
+
+    fn allow_at(&self, backend: &str, now: Instant) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(backend) else {
+            return true;
+        };
+        match *circuit {
+            Circuit::Closed { .. } => true,
+            Circuit::Open { until } if now < until => false,
+            Circuit::HalfOpen { since } if now < since + self.cooldown => false,
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => {
+                *circuit = Circuit::HalfOpen { since: now };
+                true
+            }
+        }
+    }
+
+    /// whether attempts to `backend` are currently being refused, without
+    /// claiming the half-open probe
+    pub fn is_open(&self, backend: &str) -> bool {
+        self.is_open_at(backend, Instant::now())
+    }
+
+    fn is_open_at(&self, backend: &str, now: Instant) -> bool {
+        match self.circuits.lock().unwrap().get(backend) {
+            Some(Circuit::Open { until }) => now < *until,
+            Some(Circuit::HalfOpen { since }) => now < *since + self.cooldown,
+            Some(Circuit::Closed { .. }) | None => false,
+        }
+    }
+
+    pub fn success(&self, backend: &str) {
+        self.circuits.lock().unwrap().remove(backend);
+    }
+
+    pub fn failure(&self, backend: &str) {
+        self.failure_at(backend, Instant::now())
+    }
+
+    fn failure_at(&self, backend: &str, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(backend.to_string())
+            .or_insert(Circuit::Closed { failures: 0 });
+        *circuit = match *circuit {
+            Circuit::Closed { failures } if failures + 1 < self.threshold => Circuit::Closed {
+                failures: failures + 1,
+            },
+            _ => Circuit::Open {
+                until: now + self.cooldown,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(1));
+        let backend = "127.0.0.1:80";
+        let start = Instant::now();
+
+        // closed: failures below the threshold keep letting requests through
+        breaker.failure_at(backend, start);
+        assert!(breaker.allow_at(backend, start));
+        breaker.success(backend);
+        breaker.failure_at(backend, start);
+        assert!(breaker.allow_at(backend, start));
+
+        // open
+        breaker.failure_at(backend, start);
+        assert!(!breaker.allow_at(backend, start));
+        assert!(!breaker.allow_at(backend, start + Duration::from_millis(999)));
+        assert!(breaker.allow_at("127.0.0.1:81", start));
+        assert!(breaker.is_open_at(backend, start));
+        assert!(!breaker.is_open_at(backend, start + Duration::from_secs(1)));
+
+        // half-open: one probe, a failed probe opens the circuit again
+        let later = start + Duration::from_secs(1);
+        assert!(breaker.allow_at(backend, later));
+        assert!(!breaker.allow_at(backend, later));
+        breaker.failure_at(backend, later);
+        assert!(!breaker.allow_at(backend, later + Duration::from_millis(999)));
+
+        // a successful probe closes it
+        let later = later + Duration::from_secs(1);
+        assert!(breaker.allow_at(backend, later));
+        breaker.success(backend);
+        assert!(breaker.allow_at(backend, later));
+        assert!(breaker.allow_at(backend, later));
+    }
+
+    #[test]
+    fn test_abandoned_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(1));
+        let backend = "127.0.0.1:80";
+        let start = Instant::now();
+
+        breaker.failure_at(backend, start);
+        let probe = start + Duration::from_secs(1);
+        assert!(breaker.allow_at(backend, probe));
+        assert!(!breaker.allow_at(backend, probe + Duration::from_millis(999)));
+        assert!(breaker.allow_at(backend, probe + Duration::from_secs(1)));
+    }
+}
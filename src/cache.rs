@@ -0,0 +1,386 @@
+use bytes::Bytes;
+use hyper::{
+    header::{HeaderName, HeaderValue, AGE, CACHE_CONTROL, SET_COOKIE, VARY},
+    HeaderMap, Response, StatusCode,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// in-memory LRU cache of `public` responses with a `max-age`, holding at
+/// most `capacity` bytes of bodies and headers
+pub struct Cache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// the stored variants of each key, one for every set of values of the
+    /// `vary` headers, newest last
+    entries: HashMap<String, Vec<Entry>>,
+    /// keys by the tick their variant was last used at, least recently used
+    /// first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    size: usize,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// request headers named by the response's `vary`, with the values the
+    /// stored response was made for
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    stored_at: Instant,
+    expires_at: Instant,
+    used: u64,
+    size: usize,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// the stored response for `key` if it is still fresh and was made for
+    /// the same values of the `vary` headers as `request` has
+    pub fn get(&self, key: &str, request: &HeaderMap) -> Option<Response<Bytes>> {
+        self.get_at(key, request, Instant::now())
+    }
+
+    fn get_at(&self, key: &str, request: &HeaderMap, now: Instant) -> Option<Response<Bytes>> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?.iter().rev().find(|entry| {
+            entry
+                .vary
+                .iter()
+                .all(|(name, value)| request.get(name) == value.as_ref())
+        })?;
+        if entry.expires_at <= now {
+            let used = entry.used;
+            inner.remove(key, used);
+            return None;
+        }
+
+        let mut resp = Response::new(entry.body.clone());
+        *resp.status_mut() = entry.status;
+        *resp.headers_mut() = entry.headers.clone();
+        resp.headers_mut().insert(
+            AGE,
+            HeaderValue::from(now.duration_since(entry.stored_at).as_secs()),
+        );
+        let used = entry.used;
+        inner.touch(key, used);
+        Some(resp)
+    }
+
+    /// store a response to a request with `request` headers for `max_age`,
+    /// evicting the least recently used ones to make room
+    pub fn insert(
+        &self,
+        key: String,
+        request: &HeaderMap,
+        resp: &Response<Bytes>,
+        max_age: Duration,
+    ) {
+        self.insert_at(key, request, resp, max_age, Instant::now())
+    }
+
+    fn insert_at(
+        &self,
+        key: String,
+        request: &HeaderMap,
+        resp: &Response<Bytes>,
+        max_age: Duration,
+        now: Instant,
+    ) {
+        let headers = resp.headers().clone();
+        let size = key.len()
+            + resp.body().len()
+            + headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+        if size > self.capacity {
+            return;
+        }
+        let vary: Vec<_> = vary_names(&headers)
+            .into_iter()
+            .map(|name| {
+                let value = request.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+
+        let mut inner = self.inner.lock().unwrap();
+        // only the variant for the same values is replaced
+        let replaced = inner
+            .entries
+            .get(&key)
+            .and_then(|entries| entries.iter().find(|entry| entry.vary == vary))
+            .map(|entry| entry.used);
+        if let Some(used) = replaced {
+            inner.remove(&key, used);
+        }
+        while inner.size + size > self.capacity {
+            let Some((used, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.remove(&oldest, used);
+        }
+        inner.tick += 1;
+        let used = inner.tick;
+        inner.lru.insert(used, key.clone());
+        inner.size += size;
+        inner.entries.entry(key).or_default().push(Entry {
+            status: resp.status(),
+            headers,
+            body: resp.body().clone(),
+            vary,
+            stored_at: now,
+            expires_at: now + max_age,
+            used,
+            size,
+        });
+    }
+}
+
+impl Inner {
+    /// remove the variant of `key` last used at `used`
+    fn remove(&mut self, key: &str, used: u64) {
+        let Some(entries) = self.entries.get_mut(key) else {
+            return;
+        };
+        if let Some(at) = entries.iter().position(|entry| entry.used == used) {
+            let entry = entries.remove(at);
+            self.lru.remove(&entry.used);
+            self.size -= entry.size;
+        }
+        if entries.is_empty() {
+            self.entries.remove(key);
+        }
+    }
+
+    fn touch(&mut self, key: &str, used: u64) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(key) = self.lru.remove(&used) {
+            self.lru.insert(tick, key);
+        }
+        if let Some(entry) = self
+            .entries
+            .get_mut(key)
+            .and_then(|entries| entries.iter_mut().find(|entry| entry.used == used))
+        {
+            entry.used = tick;
+        }
+    }
+}
+
+/// lowercase directives of every `cache-control` header, with their values
+fn directives(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some((name, value)) => (
+                    name.trim().to_owned(),
+                    Some(value.trim().trim_matches('"').to_owned()),
+                ),
+                None => (directive, None),
+            }
+        })
+        .collect()
+}
+
+fn has_directive(headers: &HeaderMap, name: &str) -> bool {
+    directives(headers)
+        .iter()
+        .any(|(directive, _)| directive == name)
+}
+
+/// the names listed in `vary`, `*` included
+fn vary_list(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<HeaderName> {
+    vary_list(headers)
+        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        .collect()
+}
+
+/// whether the client wants a response from the backend rather than the
+/// cache, with `no-cache` or `no-store`
+pub fn skip_lookup(request: &HeaderMap) -> bool {
+    has_directive(request, "no-cache") || has_directive(request, "no-store")
+}
+
+/// whether the client does not want the response stored either
+pub fn no_store(request: &HeaderMap) -> bool {
+    has_directive(request, "no-store")
+}
+
+/// whether the response must reach the client as the backend sent it
+pub fn no_transform(response: &HeaderMap) -> bool {
+    has_directive(response, "no-transform")
+}
+
+/// how long a response may be served from the cache: a 200 that is `public`
+/// with a `max-age` (or `s-maxage`), and none of `no-store`, `no-cache` or
+/// `private`; responses setting cookies or varying on `*` never are
+pub fn max_age(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::OK
+        || headers.contains_key(SET_COOKIE)
+        || vary_list(headers).any(|name| name == "*")
+    {
+        return None;
+    }
+    let directives = directives(headers);
+    let has = |name: &str| directives.iter().any(|(directive, _)| directive == name);
+    if !has("public") || has("no-store") || has("no-cache") || has("private") {
+        return None;
+    }
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find(|(directive, _)| directive == name)
+            .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok())
+    };
+    seconds("s-maxage")
+        .or_else(|| seconds("max-age"))
+        .filter(|&seconds| seconds > 0)
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(body: &'static str, cache_control: &'static str) -> Response<Bytes> {
+        let mut resp = Response::new(Bytes::from_static(body.as_bytes()));
+        resp.headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+        resp
+    }
+
+    #[test]
+    fn test_max_age() {
+        let headers = |cache_control: &'static str| response("", cache_control).headers().clone();
+        let ok = StatusCode::OK;
+        assert_eq!(
+            max_age(ok, &headers("public, max-age=60")),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            max_age(ok, &headers("Public, max-age=60, s-maxage=10")),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(max_age(ok, &headers("max-age=60")), None);
+        assert_eq!(max_age(ok, &headers("public, max-age=0")), None);
+        assert_eq!(max_age(ok, &headers("public, max-age=60, no-store")), None);
+        assert_eq!(max_age(ok, &headers("public, private, max-age=60")), None);
+        assert_eq!(
+            max_age(StatusCode::NOT_FOUND, &headers("public, max-age=60")),
+            None
+        );
+
+        let mut vary_any = headers("public, max-age=60");
+        vary_any.insert(VARY, HeaderValue::from_static("*"));
+        assert_eq!(max_age(ok, &vary_any), None);
+    }
+
+    #[test]
+    fn test_expiry_and_vary() {
+        let cache = Cache::new(1024);
+        let now = Instant::now();
+        let mut resp = response("hello", "public, max-age=60");
+        resp.headers_mut()
+            .insert(VARY, HeaderValue::from_static("accept-language"));
+        let mut english = HeaderMap::new();
+        english.insert("accept-language", HeaderValue::from_static("en"));
+        let mut german = HeaderMap::new();
+        german.insert("accept-language", HeaderValue::from_static("de"));
+        cache.insert_at("k".into(), &english, &resp, Duration::from_secs(60), now);
+
+        let hit = cache
+            .get_at("k", &english, now + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(hit.body(), "hello");
+        assert_eq!(hit.headers()[AGE], "5");
+        assert!(cache.get_at("k", &german, now).is_none());
+        assert!(cache
+            .get_at("k", &english, now + Duration::from_secs(60))
+            .is_none());
+        assert_eq!(cache.inner.lock().unwrap().size, 0);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let resp = response("0123456789", "public, max-age=60");
+        let size = "a".len() + 10 + "cache-control".len() + "public, max-age=60".len();
+        let cache = Cache::new(size * 2);
+        let headers = HeaderMap::new();
+        let max_age = Duration::from_secs(60);
+        cache.insert("a".into(), &headers, &resp, max_age);
+        cache.insert("b".into(), &headers, &resp, max_age);
+        assert!(cache.get("a", &headers).is_some());
+        // b is now the least recently used
+        cache.insert("c".into(), &headers, &resp, max_age);
+        assert!(cache.get("a", &headers).is_some());
+        assert!(cache.get("b", &headers).is_none());
+        assert!(cache.get("c", &headers).is_some());
+        assert_eq!(cache.inner.lock().unwrap().size, size * 2);
+    }
+
+    #[test]
+    fn test_variants() {
+        let cache = Cache::new(1024);
+        let now = Instant::now();
+        let max_age = Duration::from_secs(60);
+        let variant = |body: &'static str| {
+            let mut resp = response(body, "public, max-age=60");
+            resp.headers_mut()
+                .insert(VARY, HeaderValue::from_static("accept-language"));
+            resp
+        };
+        let mut english = HeaderMap::new();
+        english.insert("accept-language", HeaderValue::from_static("en"));
+        let mut german = HeaderMap::new();
+        german.insert("accept-language", HeaderValue::from_static("de"));
+        cache.insert_at("k".into(), &english, &variant("hello"), max_age, now);
+        cache.insert_at("k".into(), &german, &variant("hallo"), max_age, now);
+
+        assert_eq!(cache.get_at("k", &english, now).unwrap().body(), "hello");
+        assert_eq!(cache.get_at("k", &german, now).unwrap().body(), "hallo");
+        assert!(cache.get_at("k", &HeaderMap::new(), now).is_none());
+
+        // a new response replaces only the variant it was made for
+        cache.insert_at("k".into(), &english, &variant("hi"), max_age, now);
+        assert_eq!(cache.get_at("k", &english, now).unwrap().body(), "hi");
+        assert_eq!(cache.get_at("k", &german, now).unwrap().body(), "hallo");
+        assert!(cache.get_at("k", &english, now + max_age).is_none());
+        assert!(cache.get_at("k", &german, now + max_age).is_none());
+        assert_eq!(cache.inner.lock().unwrap().size, 0);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::Args;
+use clap::{parser::ValueSource, ArgMatches, CommandFactory as _, FromArgMatches as _};
+use std::error::Error;
+
+/// build the arguments from the command line, filling in everything that was
+/// not given there from the `--config` file if one is set
+pub fn load(matches: &ArgMatches) -> Result<Args, Box<dyn Error>> {
+    let args = Args::from_arg_matches(matches)?;
+    let Some(path) = &args.config else {
+        return Ok(args);
+    };
+    let file = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let file = file
+        .parse()
+        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+    merge(args, matches, file)
+}
+
+/// config file keys are the long flag names, e.g. `backend-port = 3000`
+fn merge(args: Args, matches: &ArgMatches, file: toml::Table) -> Result<Args, Box<dyn Error>> {
+    let command = Args::command();
+    let mut merged = toml::Table::try_from(&args)?;
+    for (key, value) in file {
+        let arg = command
+            .get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(key.as_str())
+                    && !["config", "check-config"].contains(&key.as_str())
+            })
+            .ok_or_else(|| format!("unknown config key {:?}", key))?;
+        if matches.value_source(arg.get_id().as_str()) != Some(ValueSource::CommandLine) {
+            merged.insert(key, value);
+        }
+    }
+    Ok(merged.try_into()?)
+}
+
+/// `--route` values in their command line form
+pub mod route_list {
+    use crate::route::Route;
+    use serde::{de::Error as _, Deserialize as _, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(routes: &[Route], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(routes.iter().map(ToString::to_string))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Route>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|route| crate::parse_route(route).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// values of repeatable flags, which the config file may also give as a
+/// single value, e.g. `proxy-port = 8100`
+pub mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    pub fn serialize<S: Serializer, T: Serialize>(
+        values: &[T],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(values)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let file = r#"
+            backend-host = "10.0.0.1"
+            backend-port = 3000
+            http2 = true
+            route = ["api=127.0.0.1:3001"]
+            wildcard-suffix = ["sslip.io"]
+        "#;
+        let matches = Args::command()
+            .try_get_matches_from(["http-proxy", "--backend-port", "9000"])
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        let args = merge(args, &matches, file.parse().unwrap()).unwrap();
+
+        assert_eq!(args.backend_host, "10.0.0.1");
+        assert_eq!(args.backend_port, 9000);
+        assert!(args.http2);
+        assert_eq!(
+            args.routes,
+            vec![crate::parse_route("api=127.0.0.1:3001").unwrap()]
+        );
+        assert_eq!(args.wildcard_suffixes, vec!["sslip.io".to_string()]);
+        assert_eq!(args.proxy_ports, vec![8100]);
+    }
+
+    #[test]
+    fn test_load() {
+        let path =
+            std::env::temp_dir().join(format!("http-proxy-test-{}.toml", std::process::id()));
+        std::fs::write(&path, "domain-suffix = \"internal\"\nproxy-port = 80\n").unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(["http-proxy", "--config", path.to_str().unwrap()])
+            .unwrap();
+        let args = load(&matches).unwrap();
+        assert_eq!(args.domain_suffix, "internal");
+        assert_eq!(args.proxy_ports, vec![80]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid() {
+        let matches = Args::command()
+            .try_get_matches_from(["http-proxy"])
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        assert!(merge(args.clone(), &matches, "no-such-flag = 1".parse().unwrap()).is_err());
+        assert!(merge(
+            args.clone(),
+            &matches,
+            "check-config = true".parse().unwrap()
+        )
+        .is_err());
+        assert!(merge(args.clone(), &matches, "route = [\"api\"]".parse().unwrap()).is_err());
+        assert!(merge(
+            args.clone(),
+            &matches,
+            "backend-port = \"http\"".parse().unwrap()
+        )
+        .is_err());
+        assert!(merge(args, &matches, "proxy-port = [\"http\"]".parse().unwrap()).is_err());
+    }
+}
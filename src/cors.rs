@@ -0,0 +1,93 @@
+use hyper::{
+    header::{
+        HeaderValue, InvalidHeaderValue, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_HEADERS,
+        ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+    },
+    HeaderMap, Method,
+};
+
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// origins given with `--cors-allow-origin`, `*` allowing any
+pub struct Cors {
+    any: bool,
+    origins: Vec<HeaderValue>,
+}
+
+impl Cors {
+    pub fn new(origins: &[String]) -> Result<Self, InvalidHeaderValue> {
+        Ok(Self {
+            any: origins.iter().any(|origin| origin == "*"),
+            origins: origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// whether the request is a CORS preflight, which the proxy answers
+    /// itself
+    pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::OPTIONS
+            && headers.contains_key(ORIGIN)
+            && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// headers of the preflight response besides the allowed origin, which
+    /// `apply` adds like on every other response
+    pub fn preflight(request: &HeaderMap, response: &mut HeaderMap) {
+        response.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(ALLOWED_METHODS),
+        );
+        if let Some(headers) = request.get(ACCESS_CONTROL_REQUEST_HEADERS) {
+            response.insert(ACCESS_CONTROL_ALLOW_HEADERS, headers.clone());
+        }
+    }
+
+    /// allow the request `origin` in the response if it is one of ours;
+    /// responses that depend on the origin say so in `vary`
+    pub fn apply(&self, origin: Option<&HeaderValue>, response: &mut HeaderMap) {
+        if self.any {
+            response.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+            return;
+        }
+        response.append(VARY, HeaderValue::from_static("origin"));
+        match origin.filter(|origin| self.origins.contains(origin)) {
+            Some(origin) => {
+                response.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            }
+            None => {
+                response.remove(ACCESS_CONTROL_ALLOW_ORIGIN);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let cors = Cors::new(&["https://app.example.com".to_string()]).unwrap();
+        let allowed = HeaderValue::from_static("https://app.example.com");
+        let other = HeaderValue::from_static("https://evil.example.com");
+
+        let mut headers = HeaderMap::new();
+        cors.apply(Some(&allowed), &mut headers);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], allowed);
+        assert_eq!(headers[VARY], "origin");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, other.clone());
+        cors.apply(Some(&other), &mut headers);
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let cors = Cors::new(&["*".to_string()]).unwrap();
+        let mut headers = HeaderMap::new();
+        cors.apply(Some(&other), &mut headers);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+}
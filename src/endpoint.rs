@@ -0,0 +1,141 @@
+use crate::{listener, listener::Listener, shutdown_requested, tokio_io::TokioIo, ACCEPT_BACKOFF};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{server::conn::http1, service::service_fn, Request, Response, StatusCode};
+use std::convert::Infallible;
+use tokio::sync::watch;
+use tracing::{debug, error, warn};
+
+/// serve a small HTTP endpoint such as `--metrics-port` or `--admin-port`
+/// until shutdown is requested; `respond` gives the content type and body for
+/// a path it knows, every other path is a 404
+pub async fn serve<L, F>(
+    listener: L,
+    name: &'static str,
+    respond: F,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    L: Listener,
+    F: Fn(&str) -> Option<(&'static str, String)> + Clone + Send + Sync + 'static,
+{
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) if listener::is_transient(&e) => {
+                    warn!("Error when accepting {} connection, retrying: {:?}", name, e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                        _ = shutdown_requested(&mut shutdown) => break,
+                    }
+                }
+                Err(e) => {
+                    error!("Error when accepting {} connection {:?}", name, e);
+                    break;
+                }
+            },
+            _ = shutdown_requested(&mut shutdown) => break,
+        };
+
+        let respond = respond.clone();
+        tokio::task::spawn(async move {
+            let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                let resp = match respond(req.uri().path()) {
+                    Some((content_type, body)) => Response::builder()
+                        .header("content-type", content_type)
+                        .body(Full::new(Bytes::from(body))),
+                    None => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Full::new(Bytes::new())),
+                };
+                async move { Ok::<_, Infallible>(resp.unwrap()) }
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Failed to serve {} connection: {:?}", name, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        io,
+        net::SocketAddr,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// fails its first accept the way a connection aborted before it was
+    /// accepted does
+    struct Flaky {
+        inner: TcpListener,
+        failed: AtomicBool,
+    }
+
+    impl Listener for Flaky {
+        type Stream = TcpStream;
+
+        async fn accept(&self) -> io::Result<(TcpStream, Option<SocketAddr>)> {
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                return Err(io::ErrorKind::ConnectionAborted.into());
+            }
+            let (stream, peer) = self.inner.accept().await?;
+            Ok((stream, Some(peer)))
+        }
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    path
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let inner = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = inner.local_addr().unwrap();
+        let listener = Flaky {
+            inner,
+            failed: AtomicBool::new(false),
+        };
+        let respond = |path: &str| (path == "/ping").then(|| ("text/plain", "pong".to_string()));
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve(listener, "test", respond, shutdown_rx));
+
+        // still serving after the failed accept
+        let response = get(addr, "/ping").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("content-type: text/plain\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("pong"), "{}", response);
+        let response = get(addr, "/nothing").await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+
+        shutdown_tx.send(true).unwrap();
+        server.await.unwrap();
+    }
+}
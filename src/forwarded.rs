@@ -0,0 +1,86 @@
+use hyper::{header::FORWARDED, HeaderMap};
+use std::net::{IpAddr, SocketAddr};
+
+/// the client address in the last `forwarded` element (RFC 7239), the one
+/// the proxy in front of us added; `None` if that element has no `for=` or
+/// an `unknown` or obfuscated one, as earlier elements could be made up
+pub fn client(headers: &HeaderMap) -> Option<IpAddr> {
+    let last = headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .next_back()?;
+    last.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("for") {
+            return None;
+        }
+        let node = value.trim().trim_matches('"');
+        node.parse::<IpAddr>()
+            .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+            .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+            .ok()
+    })
+}
+
+/// the element describing the hop from `peer` to us, e.g.
+/// `for="[2001:db8::1]";proto=https`
+pub fn element(peer: Option<IpAddr>, tls: bool) -> String {
+    let node = match peer {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        None => "unknown".to_string(),
+    };
+    format!("for={};proto={}", node, if tls { "https" } else { "http" })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(FORWARDED, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_client() {
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        assert_eq!(client(&headers(&["for=1.2.3.4"])), ip("1.2.3.4"));
+        assert_eq!(
+            client(&headers(&["for=192.0.2.60;proto=http;by=203.0.113.43"])),
+            ip("192.0.2.60")
+        );
+        assert_eq!(
+            client(&headers(&["For=\"[2001:db8:cafe::17]:4711\""])),
+            ip("2001:db8:cafe::17")
+        );
+        assert_eq!(client(&headers(&["for=\"1.2.3.4:80\""])), ip("1.2.3.4"));
+        assert_eq!(
+            client(&headers(&["for=1.1.1.1, for=2.2.2.2", "for=3.3.3.3"])),
+            ip("3.3.3.3")
+        );
+        assert_eq!(client(&headers(&["for=1.1.1.1, for=unknown"])), None);
+        assert_eq!(client(&headers(&["for=_hidden"])), None);
+        assert_eq!(client(&headers(&["proto=https"])), None);
+        assert_eq!(client(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_element() {
+        assert_eq!(
+            element(Some("10.0.0.1".parse().unwrap()), false),
+            "for=10.0.0.1;proto=http"
+        );
+        assert_eq!(
+            element(Some("::1".parse().unwrap()), true),
+            "for=\"[::1]\";proto=https"
+        );
+        assert_eq!(element(None, false), "for=unknown;proto=http");
+    }
+}
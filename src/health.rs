@@ -0,0 +1,218 @@
+use crate::{shutdown_requested, tokio_io::TokioIo};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{client::conn::http1, Request};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::watch,
+    time::timeout,
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+use tracing::{debug, info, warn};
+
+/// result of the last health check of each backend; backends that were never
+/// checked count as up
+pub type HealthMap = Arc<RwLock<HashMap<SocketAddr, Status>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub up: bool,
+    /// when the backend last passed its check after failing one
+    pub recovered_at: Option<Instant>,
+}
+
+pub fn is_up(health: &HealthMap, addr: SocketAddr) -> bool {
+    health
+        .read()
+        .unwrap()
+        .get(&addr)
+        .is_none_or(|status| status.up)
+}
+
+/// fraction of its weight a backend gets within `window` of recovering,
+/// growing linearly from nothing; 1 once the window is over or if it never
+/// failed a check
+pub fn slow_start_share(health: &HealthMap, addr: SocketAddr, window: Duration) -> f64 {
+    let recovered_at = health
+        .read()
+        .unwrap()
+        .get(&addr)
+        .and_then(|status| status.recovered_at);
+    match recovered_at {
+        Some(at) if !window.is_zero() => {
+            (at.elapsed().as_secs_f64() / window.as_secs_f64()).min(1.0)
+        }
+        _ => 1.0,
+    }
+}
+
+/// check every backend each `interval` until shutdown is requested, over TLS
+/// when a connector is given
+pub async fn run(
+    backends: Vec<SocketAddr>,
+    path: String,
+    interval: Duration,
+    check_timeout: Duration,
+    tls: Option<TlsConnector>,
+    health: HealthMap,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        check_all(&backends, &path, check_timeout, tls.as_ref(), &health).await;
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown_requested(&mut shutdown) => break,
+        }
+    }
+}
+
+async fn check_all(
+    backends: &[SocketAddr],
+    path: &str,
+    check_timeout: Duration,
+    tls: Option<&TlsConnector>,
+    health: &HealthMap,
+) {
+    for &addr in backends {
+        let up = timeout(check_timeout, check(addr, path, tls))
+            .await
+            .unwrap_or(false);
+        let mut health = health.write().unwrap();
+        let previous = health.get(&addr).copied();
+        let was_up = previous.is_none_or(|status| status.up);
+        let recovered_at = match (was_up, up) {
+            (true, false) => {
+                warn!("backend {} failed its health check, marking it down", addr);
+                None
+            }
+            (false, true) => {
+                info!("backend {} passed its health check, marking it up", addr);
+                Some(Instant::now())
+            }
+            _ => previous.and_then(|status| status.recovered_at),
+        };
+        health.insert(addr, Status { up, recovered_at });
+    }
+}
+
+/// whether `GET <path>` on the backend answers with a 2xx status
+async fn check(addr: SocketAddr, path: &str, tls: Option<&TlsConnector>) -> bool {
+    let result = async {
+        let stream = TcpStream::connect(addr).await?;
+        match tls {
+            Some(connector) => {
+                let server_name = ServerName::IpAddress(addr.ip().into());
+                let stream = connector.connect(server_name, stream).await?;
+                get(stream, addr, path).await
+            }
+            None => get(stream, addr, path).await,
+        }
+    }
+    .await;
+    result.unwrap_or_else(|err| {
+        debug!("health check of backend {} failed: {:?}", addr, err);
+        false
+    })
+}
+
+async fn get<S>(
+    stream: S,
+    addr: SocketAddr,
+    path: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(conn);
+    let req = Request::builder()
+        .uri(path)
+        .header("host", addr.to_string())
+        .body(Empty::<Bytes>::new())?;
+    let resp = sender.send_request(req).await?;
+    Ok(resp.status().is_success())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{server::conn::http1 as server, service::service_fn, Response, StatusCode};
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    async fn spawn_backend(status: StatusCode) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(server::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req: Request<hyper::body::Incoming>| async move {
+                        let status = match req.uri().path() {
+                            "/health" => status,
+                            _ => StatusCode::NOT_FOUND,
+                        };
+                        let mut resp = Response::new(Empty::<Bytes>::new());
+                        *resp.status_mut() = status;
+                        Ok::<_, Infallible>(resp)
+                    }),
+                ));
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_check_all() {
+        let healthy = spawn_backend(StatusCode::OK).await;
+        let failing = spawn_backend(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = closed.local_addr().unwrap();
+        drop(closed);
+
+        let health = HealthMap::default();
+        let backends = [healthy, failing, down];
+        assert!(backends.iter().all(|addr| is_up(&health, *addr)));
+
+        check_all(&backends, "/health", Duration::from_secs(1), None, &health).await;
+        assert!(is_up(&health, healthy));
+        assert!(!is_up(&health, failing));
+        assert!(!is_up(&health, down));
+    }
+
+    #[tokio::test]
+    async fn test_slow_start() {
+        let backend = spawn_backend(StatusCode::OK).await;
+        let window = Duration::from_millis(200);
+        let health = HealthMap::default();
+        check_all(&[backend], "/health", Duration::from_secs(1), None, &health).await;
+        assert_eq!(slow_start_share(&health, backend, window), 1.0);
+
+        health.write().unwrap().insert(
+            backend,
+            Status {
+                up: false,
+                recovered_at: None,
+            },
+        );
+        check_all(&[backend], "/health", Duration::from_secs(1), None, &health).await;
+        assert!(is_up(&health, backend));
+        let share = slow_start_share(&health, backend, window);
+        assert!(share < 0.5, "{}", share);
+
+        tokio::time::sleep(window / 2).await;
+        let later = slow_start_share(&health, backend, window);
+        assert!(later > share && later < 1.0, "{}", later);
+
+        tokio::time::sleep(window).await;
+        assert_eq!(slow_start_share(&health, backend, window), 1.0);
+    }
+}
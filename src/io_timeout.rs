@@ -0,0 +1,134 @@
+use pin_project_lite::pin_project;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+pin_project! {
+    /// stream failing with `TimedOut` when a read or write makes no progress
+    /// for `timeout`, never without one
+    pub struct TimeoutIo<S> {
+        #[pin]
+        inner: S,
+        timeout: Option<Duration>,
+        read_deadline: Option<Pin<Box<Sleep>>>,
+        write_deadline: Option<Pin<Box<Sleep>>>,
+    }
+}
+
+impl<S> TimeoutIo<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            read_deadline: None,
+            write_deadline: None,
+        }
+    }
+}
+
+/// `result` unless the operation has been pending for longer than `timeout`
+fn check<T>(
+    result: Poll<io::Result<T>>,
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Option<Duration>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<T>> {
+    let Some(timeout) = timeout else {
+        return result;
+    };
+    if result.is_ready() {
+        *deadline = None;
+        return result;
+    }
+    let deadline = deadline.get_or_insert_with(|| Box::pin(sleep(timeout)));
+    match deadline.as_mut().poll(cx) {
+        Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no progress for {}ms", timeout.as_millis()),
+        ))),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for TimeoutIo<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let result = this.inner.poll_read(cx, buf);
+        check(result, this.read_deadline, *this.timeout, cx)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for TimeoutIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let result = this.inner.poll_write(cx, buf);
+        check(result, this.write_deadline, *this.timeout, cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let result = this.inner.poll_flush(cx);
+        check(result, this.write_deadline, *this.timeout, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let result = this.inner.poll_write_vectored(cx, bufs);
+        check(result, this.write_deadline, *this.timeout, cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut client = TimeoutIo::new(client, Some(Duration::from_millis(50)));
+        server.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let (client, _server) = tokio::io::duplex(4);
+        let mut client = TimeoutIo::new(client, Some(Duration::from_millis(50)));
+        let err = client.write_all(b"more than fits").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
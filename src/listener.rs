@@ -0,0 +1,145 @@
+use crate::socket::SocketOptions;
+use std::{future::Future, io, net::SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+/// source of client connections; the peer address is `None` for transports
+/// without one such as Unix sockets
+pub trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&self)
+        -> impl Future<Output = io::Result<(Self::Stream, Option<SocketAddr>)>> + Send;
+
+    /// apply the TCP `options` to an accepted stream, if it is a TCP one
+    fn set_options(_stream: &Self::Stream, _options: &SocketOptions) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, Option<SocketAddr>)> {
+        let (stream, peer) = TcpListener::accept(self).await?;
+        Ok((stream, Some(peer)))
+    }
+
+    fn set_options(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+        options.apply(stream)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&self) -> io::Result<(tokio::net::UnixStream, Option<SocketAddr>)> {
+        let (stream, _) = tokio::net::UnixListener::accept(self).await?;
+        Ok((stream, None))
+    }
+}
+
+/// whether accepting may go on after `err`: it concerned only the connection
+/// being accepted, or resources such as file descriptors that free up again
+pub fn is_transient(err: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    // EMFILE, ENFILE and ENOBUFS, or their winsock equivalents
+    #[cfg(target_os = "linux")]
+    const OUT_OF_RESOURCES: [i32; 3] = [24, 23, 105];
+    #[cfg(all(unix, not(target_os = "linux")))]
+    const OUT_OF_RESOURCES: [i32; 3] = [24, 23, 55];
+    #[cfg(windows)]
+    const OUT_OF_RESOURCES: [i32; 2] = [10024, 10055];
+
+    matches!(
+        err.kind(),
+        ConnectionAborted
+            | ConnectionReset
+            | ConnectionRefused
+            | Interrupted
+            | WouldBlock
+            | TimedOut
+            | OutOfMemory
+    ) || err
+        .raw_os_error()
+        .is_some_and(|code| OUT_OF_RESOURCES.contains(&code))
+}
+
+/// bind a Unix socket at `path`, replacing a stale socket file left behind by
+/// a previous run
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt as _;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+/// first file descriptor systemd passes listening sockets on
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// the TCP sockets systemd passed with socket activation, as announced by
+/// `LISTEN_FDS` for the process in `LISTEN_PID`
+#[cfg(unix)]
+pub fn systemd_listeners() -> io::Result<Vec<TcpListener>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let count = listen_fds(pid.as_deref(), fds.as_deref(), std::process::id())?;
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands these descriptors to this process, and
+            // nothing else in it takes ownership of them
+            unsafe { adopt(fd) }
+        })
+        .collect()
+}
+
+/// how many descriptors `LISTEN_FDS` announces, if `LISTEN_PID` is `own_pid`
+#[cfg(unix)]
+pub fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> io::Result<i32> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Err(invalid("LISTEN_PID and LISTEN_FDS are not set".to_string()));
+    };
+    if pid.parse::<u32>().ok() != Some(own_pid) {
+        return Err(invalid(format!(
+            "LISTEN_PID {} is not this process ({})",
+            pid, own_pid
+        )));
+    }
+    match fds.parse::<i32>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(invalid(format!("LISTEN_FDS {:?} names no sockets", fds))),
+    }
+}
+
+/// a tokio listener owning `fd`, a TCP socket that is already listening
+///
+/// # Safety
+///
+/// `fd` has to be open and owned by nobody else
+#[cfg(unix)]
+pub unsafe fn adopt(fd: std::os::fd::RawFd) -> io::Result<TcpListener> {
+    use std::os::fd::FromRawFd as _;
+
+    let socket = socket2::Socket::from_raw_fd(fd);
+    let is_tcp =
+        socket.r#type()? == socket2::Type::STREAM && socket.local_addr()?.as_socket().is_some();
+    if !is_tcp {
+        // the descriptor is left open for whoever passed it
+        std::mem::forget(socket);
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("file descriptor {} is not a TCP socket", fd),
+        ));
+    }
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
@@ -1,36 +1,192 @@
+use auth::BasicAuth;
+use body::{Compress, Deadline, Timed};
+use breaker::CircuitBreaker;
 use bytes::Bytes;
-use clap::Parser;
-use http_body_util::{combinators::BoxBody, BodyExt};
-use hyper::client::conn::http1::Builder;
-use hyper::server::conn::http1;
+use cache::Cache;
+use clap::{CommandFactory as _, Parser, ValueEnum};
+use cors::Cors;
+use health::HealthMap;
+use http_body_util::{combinators::BoxBody, BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::Body as _;
+use hyper::client::conn::http1::{Builder, SendRequest};
+use hyper::header::{
+    HeaderName, HeaderValue, InvalidHeaderName, ACCEPT, ACCEPT_ENCODING, ALLOW, AUTHORIZATION,
+    CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, COOKIE, ETAG,
+    FORWARDED, HOST, LOCATION, ORIGIN, SEC_WEBSOCKET_PROTOCOL, SET_COOKIE, TE, UPGRADE, VARY,
+    WWW_AUTHENTICATE,
+};
+use hyper::http::uri::Authority;
+use hyper::server::conn::{http1, http2};
 use hyper::service::service_fn;
 use hyper::upgrade::OnUpgrade;
-use hyper::{HeaderMap, Request, Response, StatusCode};
-use once_cell::sync::Lazy;
-use regex::Regex;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
+use io_timeout::TimeoutIo;
+use ipnet::IpNet;
+use listener::Listener;
+use metrics::Metrics;
+use pool::Pool;
+use rate_limit::RateLimiter;
+use regex::{Regex, RegexSet};
+use route::{Balancer, Limits, Route, Target, Upstream};
+use serde::{Deserialize, Serialize};
+use socket::SocketOptions;
 use std::{
-    net::{Ipv4Addr, SocketAddr},
-    str::FromStr as _,
+    collections::HashMap,
+    error::Error as _,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::io::copy_bidirectional;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::signal::{
-    ctrl_c,
-    unix::{signal, SignalKind},
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncWrite, AsyncWriteExt as _};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+#[cfg(windows)]
+use tokio::signal::{ctrl_c, windows::ctrl_close};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{timeout, timeout_at};
+use tokio_rustls::rustls::pki_types::{InvalidDnsNameError, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{debug, error, field, info, info_span, warn, Instrument as _, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::{self, MakeWriter},
+    prelude::*,
+    registry::LookupSpan,
+    EnvFilter, Layer,
 };
-use tracing::{debug, error, info};
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use uuid::Uuid;
 
+mod admin;
+mod auth;
+mod body;
+mod breaker;
+mod cache;
+mod config;
+mod cors;
+mod endpoint;
+mod forwarded;
+mod health;
+mod idle;
+mod io_timeout;
+mod listener;
+mod metrics;
+mod pool;
+mod proxy_protocol;
+mod rate_limit;
+mod route;
+mod socket;
+mod telemetry;
+mod tls;
 mod tokio_io;
 
-#[derive(Parser, Debug, Clone)]
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// largest request or response body `--log-bodies` buffers and logs
+const LOG_BODY_LIMIT: u64 = 8192;
+
+/// delay before the first connect retry, growing linearly with each attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// pause after a transient accept error, giving file descriptors a chance to
+/// free up
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// longest host name DNS allows, and longest label in it
+const MAX_HOST_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// smallest read buffer hyper accepts for HTTP/1
+const MIN_HEADER_BYTES: u32 = 8192;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// casing of HTTP/1 header names written to clients and backends
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum HeaderCase {
+    /// names keep the case they arrived in, those the proxy adds are
+    /// title-cased
+    Preserve,
+    /// every name is title-cased
+    Title,
+    /// every name is lowercase
+    Lower,
+}
+
+/// content coding `--compress` can apply
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum CompressAlgo {
+    Gzip,
+    /// brotli
+    Br,
+}
+
+impl CompressAlgo {
+    fn coding(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Br => "br",
+        }
+    }
+}
+
+impl HeaderCase {
+    fn preserve(self) -> bool {
+        self == Self::Preserve
+    }
+
+    fn title(self) -> bool {
+        self != Self::Lower
+    }
+}
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 #[command(author, version, about, long_about = None)]
+#[serde(rename_all = "kebab-case")]
 struct Args {
+    /// TOML file with defaults for any of the other flags, keyed by flag name;
+    /// on Unix SIGHUP re-reads it, except for the listen addresses and the
+    /// health-checked targets
+    #[arg(long)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
+    /// validate the configuration, including TLS files and the listen
+    /// address, then exit without serving
+    #[arg(long)]
+    #[serde(skip)]
+    check_config: bool,
+
+    /// `json` writes one object per event with the access log fields as keys
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
     #[arg(long, default_value_t = String::from("0.0.0.0"))]
     proxy_host: String,
 
-    #[arg(long, default_value_t = 8100)]
-    proxy_port: u16,
+    /// may be given several times to listen on more than one port
+    #[arg(long = "proxy-port", default_value = "8100")]
+    #[serde(rename = "proxy-port", with = "config::one_or_many")]
+    proxy_ports: Vec<u16>,
+
+    /// connections the kernel holds for each listening TCP socket until
+    /// they are accepted, capped by the system limit (`somaxconn` on Linux)
+    #[arg(long, default_value_t = 1024)]
+    listen_backlog: u32,
 
     #[arg(long, default_value_t = String::from("localhost"))]
     backend_host: String,
@@ -38,190 +194,7921 @@ struct Args {
     #[arg(long, default_value_t = 80)]
     backend_port: u16,
 
+    /// connect to the address embedded in the host, e.g. 10.0.0.5 for
+    /// `foo.10.0.0.5.nip.io`, on `--backend-port` instead of `--backend-host`
+    #[arg(long)]
+    backend_from_host: bool,
+
+    /// refuse to connect to backends, embedded addresses and `CONNECT` targets
+    /// alike, resolving to loopback, private or link-local addresses such as
+    /// the cloud metadata service at 169.254.169.254
+    #[arg(long)]
+    deny_private: bool,
+
+    /// speak TLS to the backends, with the backend host as the server name
+    #[arg(long)]
+    backend_tls: bool,
+
+    /// accept any backend certificate, e.g. a self-signed one in development
+    #[arg(long, requires = "backend_tls")]
+    backend_tls_insecure: bool,
+
     #[arg(long, default_value_t = String::from("localhost"))]
     domain_suffix: String,
+
+    /// use another suffix than `--domain-suffix` for subdomains ending in
+    /// `<match>`, which it replaces, e.g. `admin=admin.internal` sends
+    /// `foo.admin.192.168.1.1.nip.io` to `foo.admin.internal`; the longest
+    /// match wins
+    #[arg(long = "suffix-map", value_parser = suffix_map_arg)]
+    #[serde(rename = "suffix-map")]
+    suffix_maps: Vec<String>,
+
+    /// host header sent to the backend: `suffix` for the subdomain followed
+    /// by `--domain-suffix` (or a `--suffix-map` one), `preserve` for the one
+    /// the client sent, or `fixed:<host>`
+    #[arg(long, default_value_t = String::from("suffix"), value_parser = host_rewrite_arg)]
+    host_rewrite: String,
+
+    /// act as a forward proxy for `CONNECT host:port`, tunnelling the
+    /// connection to that address
+    #[arg(long)]
+    enable_connect: bool,
+
+    /// keep the port of the client's host, e.g. `:8888` of
+    /// `foo.192.168.1.1.nip.io:8888`, in the `suffix` rewrite; `--backend-port`
+    /// is never added
+    #[arg(long)]
+    preserve_port: bool,
+
+    /// header name casing on HTTP/1 connections, both to clients and to
+    /// backends
+    #[arg(long, value_enum, default_value_t = HeaderCase::Preserve)]
+    header_case: HeaderCase,
+
+    /// only proxy subdomains matching one of these regexes in full, e.g.
+    /// `team-[a-z]+`, and answer everything else with 403
+    #[arg(long = "allow-subdomain", value_parser = allow_subdomain_arg)]
+    #[serde(rename = "allow-subdomain")]
+    allow_subdomains: Vec<String>,
+
+    /// subdomain used for hosts that have none, e.g. `www` to send
+    /// `192.168.1.1.nip.io` to `www.<domain-suffix>`; empty sends them to the
+    /// bare `--domain-suffix`, and without it they are answered with 421
+    #[arg(long, value_parser = subdomain_arg)]
+    default_subdomain: Option<String>,
+
+    /// wildcard DNS domain the proxy is reached through, e.g. `sslip.io`
+    #[arg(long = "wildcard-suffix", default_value = "nip.io")]
+    #[serde(rename = "wildcard-suffix")]
+    wildcard_suffixes: Vec<String>,
+
+    /// character between the numbers of the IPv4 address in the host, `.`,
+    /// `-` or `_`; repeat it to accept several, e.g. `-` as well for
+    /// `foo.192-168-1-1.sslip.io`
+    #[arg(long = "ip-separator", default_value = ".", value_parser = ip_separator_arg)]
+    #[serde(rename = "ip-separator")]
+    ip_separators: Vec<char>,
+
+    #[arg(long, default_value_t = 5000)]
+    connect_timeout_ms: u64,
+
+    /// path answered directly by the proxy for hosts that are not nip.io domains
+    #[arg(long, default_value_t = String::from("/healthz"))]
+    health_path: String,
+
+    /// serve HTTP/2, negotiated through ALPN with TLS and with prior knowledge
+    /// otherwise; connection upgrades (e.g. WebSocket) are only available to
+    /// HTTP/1 clients
+    #[arg(long)]
+    http2: bool,
+
+    /// answer everything but the health path with 503 without contacting the
+    /// backends; SIGUSR1 toggles this at runtime on Unix
+    #[arg(long)]
+    maintenance: bool,
+
+    /// body of the maintenance responses
+    #[arg(long, default_value_t = String::from("down for maintenance\n"))]
+    maintenance_body: String,
+
+    /// `retry-after` seconds sent with the maintenance responses
+    #[arg(long)]
+    maintenance_retry_after: Option<u64>,
+
+    /// serve prometheus metrics at `/metrics` on this port
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// serve JSON views of the backends, the configuration in effect and
+    /// the open client connections under `/admin/` on this port
+    #[arg(long)]
+    admin_port: Option<u16>,
+
+    /// address the `--admin-port` listener binds to
+    #[arg(long, default_value_t = String::from("127.0.0.1"))]
+    admin_host: String,
+
+    /// send a span for every request to this OTLP/HTTP collector, e.g.
+    /// `http://localhost:4318/v1/traces`, continuing the client's
+    /// `traceparent` and passing the span's own on to the backend; read at
+    /// startup only
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// listen on this Unix socket instead of `--proxy-host`/`--proxy-port`
+    #[cfg(unix)]
+    #[arg(long)]
+    proxy_unix_socket: Option<PathBuf>,
+
+    /// serve on the TCP sockets systemd passes with socket activation
+    /// (`LISTEN_FDS`) instead of binding `--proxy-host`/`--proxy-port`
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "proxy_unix_socket")]
+    systemd_socket_activation: bool,
+
+    /// shut down on this signal as well as SIGINT and SIGTERM, e.g.
+    /// `SIGUSR2` or a number
+    #[cfg(unix)]
+    #[arg(long = "shutdown-signal", value_parser = shutdown_signal_arg)]
+    #[serde(rename = "shutdown-signal")]
+    shutdown_signals: Vec<String>,
+
+    /// shut down only on the `--shutdown-signal`s, not on SIGINT and SIGTERM
+    #[cfg(unix)]
+    #[arg(long, requires = "shutdown_signals")]
+    no_default_shutdown_signals: bool,
+
+    /// answer plaintext requests with a redirect to the same host and path
+    /// over https instead of proxying them; the health path is still served
+    #[arg(long)]
+    redirect_https: bool,
+
+    /// PEM certificate chain; enables TLS on the listener together with `--tls-key`
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// upper bound for the whole exchange with the backend, including
+    /// streaming the response body
+    #[arg(long)]
+    request_timeout_ms: Option<u64>,
+
+    /// tell the backend when the request times out with an
+    /// `x-request-deadline` header in milliseconds since the Unix epoch, and
+    /// take an earlier one the client sends as the request's deadline
+    #[arg(long)]
+    propagate_deadline: bool,
+
+    /// tear down backend connections on which a read or write makes no
+    /// progress for this long, answering 504 if the response has not started;
+    /// idle pooled connections are closed after this long as well, upgraded
+    /// ones are not affected
+    #[arg(long)]
+    backend_io_timeout_ms: Option<u64>,
+
+    /// reject request bodies larger than this with 413
+    #[arg(long)]
+    max_body_bytes: Option<usize>,
+
+    /// buffer limit for the request line and headers, at least 8192; larger
+    /// requests are answered with 431
+    #[arg(long, value_parser = clap::value_parser!(u32).range(MIN_HEADER_BYTES as i64..))]
+    max_header_bytes: Option<u32>,
+
+    /// requests per second allowed for each client address
+    #[arg(long, value_parser = rate_limit_arg)]
+    rate_limit: Option<f64>,
+
+    /// connect retries for GET, HEAD, OPTIONS and TRACE requests
+    #[arg(long, default_value_t = 0)]
+    max_retries: u32,
+
+    /// idle keep-alive connections kept per backend, 0 disables pooling
+    #[arg(long, default_value_t = 8)]
+    max_idle_per_host: usize,
+
+    /// requests in flight to the same backend at once, until their response
+    /// bodies are done; read at startup only
+    #[arg(long)]
+    max_connections_per_host: Option<usize>,
+
+    /// how long a request waits for one of the `--max-connections-per-host`
+    /// before it is answered with 503
+    #[arg(long, default_value_t = 1000)]
+    pool_wait_timeout_ms: u64,
+
+    /// expect a PROXY protocol v1 or v2 header in front of every connection,
+    /// as sent by HAProxy or an AWS NLB, and take the client address from it
+    #[arg(long)]
+    accept_proxy_protocol: bool,
+
+    /// take the client address used for rate limits and the access log from
+    /// the `for=` the proxy in front added to the `Forwarded` header, which
+    /// is passed on with our own element appended; otherwise the header the
+    /// client sent is replaced
+    #[arg(long)]
+    trust_forwarded: bool,
+
+    /// keep the `X-Forwarded-For` a client sends, appending to it, only if
+    /// the client is in one of these networks (e.g. `10.0.0.0/8` or a bare
+    /// address); it is replaced for everyone else
+    #[arg(long, value_parser = trusted_proxy_arg)]
+    trusted_proxies: Vec<String>,
+
+    /// send a PROXY protocol v2 header with the client address on every
+    /// backend connection; backend connections are not pooled then
+    #[arg(long)]
+    send_proxy_protocol: bool,
+
+    /// disable Nagle's algorithm on client and backend sockets, sending small
+    /// writes right away
+    #[arg(long)]
+    tcp_nodelay: bool,
+
+    /// send TCP keepalive probes on client and backend sockets after this
+    /// long without traffic, and as often after that, to notice dead peers
+    #[arg(long)]
+    tcp_keepalive_ms: Option<u64>,
+
+    /// connections served at once; further ones wait in the listen backlog,
+    /// or in the `--queue-depth` queue
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// let this many connections over `--max-connections` wait in a queue
+    /// for one of that many workers instead, answering those that find it
+    /// full with 503
+    #[arg(long, requires = "max_connections")]
+    queue_depth: Option<usize>,
+
+    /// close client connections after this long without reading or writing
+    /// anything, and give up on request headers that take longer to arrive
+    #[arg(long)]
+    idle_timeout_ms: Option<u64>,
+
+    /// drop client connections whose PROXY protocol header or TLS handshake
+    /// has not arrived or finished after this long
+    #[arg(long, default_value_t = 10000)]
+    handshake_timeout_ms: u64,
+
+    /// how long open connections may keep running after a shutdown signal
+    #[arg(long, default_value_t = 10000)]
+    shutdown_grace_ms: u64,
+
+    /// consecutive connect failures after which a backend is not tried again
+    /// until `--breaker-cooldown-ms` has passed
+    #[arg(long)]
+    breaker_threshold: Option<u32>,
+
+    #[arg(long, default_value_t = 30000)]
+    breaker_cooldown_ms: u64,
+
+    /// check the route targets this often and stop balancing to those that
+    /// fail
+    #[arg(long)]
+    health_check_interval_ms: Option<u64>,
+
+    /// requested from route targets by the health check, anything but 2xx
+    /// marks them down
+    #[arg(long, default_value_t = String::from("/health"))]
+    health_check_path: String,
+
+    /// targets passing their health check again get a share of their weight
+    /// growing from nothing to all of it over this many milliseconds
+    #[arg(long, requires = "health_check_interval_ms")]
+    slow_start_ms: Option<u64>,
+
+    /// remove this leading path segment, e.g. `/api`, before forwarding
+    #[arg(long)]
+    strip_prefix: Option<String>,
+
+    /// set a header on every forwarded request, e.g. `X-Env:staging`,
+    /// replacing what the client sent
+    #[arg(long = "add-request-header", value_parser = header_arg)]
+    #[serde(rename = "add-request-header")]
+    add_request_headers: Vec<String>,
+
+    /// set a header on every response, including the proxy's own errors,
+    /// replacing what the backend sent
+    #[arg(long = "add-response-header", value_parser = header_arg)]
+    #[serde(rename = "add-response-header")]
+    add_response_headers: Vec<String>,
+
+    /// drop a header the client sent before forwarding the request, e.g.
+    /// `Origin`; the headers the proxy adds, such as `x-forwarded-for`, are
+    /// set afterwards, as is `--add-request-header`, and `host` always is
+    #[arg(long = "remove-request-header", value_parser = remove_request_header_arg)]
+    #[serde(rename = "remove-request-header")]
+    remove_request_headers: Vec<String>,
+
+    /// drop a header the backend sent before returning the response; the
+    /// headers the proxy adds, such as the request id, are set afterwards, as
+    /// is `--add-response-header`
+    #[arg(long = "remove-response-header", value_parser = header_name_arg)]
+    #[serde(rename = "remove-response-header")]
+    remove_response_headers: Vec<String>,
+
+    /// header carrying the request id, passed through when the client sends
+    /// one and generated otherwise; empty disables request ids
+    #[arg(long, default_value_t = String::from("X-Request-Id"))]
+    request_id_header: String,
+
+    /// header telling the backend the subdomain the request came in on, e.g.
+    /// `foo.bar` for `foo.bar.192.168.1.1.nip.io`; empty disables it
+    #[arg(long, default_value_t = String::from("X-Forwarded-Subdomain"))]
+    subdomain_header: String,
+
+    /// directory with `<status>.html` pages, e.g. `502.html`, served instead
+    /// of the plain text body of errors the proxy responds with itself
+    #[arg(long)]
+    error_page_dir: Option<PathBuf>,
+
+    /// say why the backend could not be reached in an `X-Proxy-Error` header
+    /// on the proxy's 502 and 504 responses, e.g. `connect failed: connection
+    /// refused`; meant for debugging, as it tells clients about the backend
+    #[arg(long)]
+    expose_error_detail: bool,
+
+    /// answer with status `to` instead when the backend responds with
+    /// `from`, e.g. `500:503`; the body is replaced by the error page for
+    /// `to` if `--error-page-dir` has one
+    #[arg(long = "rewrite-status", value_parser = status_rewrite_arg)]
+    #[serde(rename = "rewrite-status")]
+    status_rewrites: Vec<String>,
+
+    /// require HTTP basic auth with these `user:password` credentials for
+    /// everything but the health path; the `authorization` header is not
+    /// passed on to the backend
+    #[arg(long, value_parser = auth::parse_credentials)]
+    basic_auth: Vec<String>,
+
+    /// origin allowed to call the proxied services from a browser, e.g.
+    /// `http://localhost:3000`, or `*` for any; the proxy answers CORS
+    /// preflight requests itself then
+    #[arg(long = "cors-allow-origin")]
+    #[serde(rename = "cors-allow-origin")]
+    cors_allow_origins: Vec<String>,
+
+    /// log request and response bodies of a known length up to 8 KiB at debug
+    /// level, which means buffering them; upgraded connections are never
+    /// logged
+    #[arg(long)]
+    log_bodies: bool,
+
+    /// keep browsers on the route target they were first balanced to with a
+    /// cookie of this name; they move on when it is down
+    #[arg(long, value_parser = cookie_name_arg)]
+    sticky_cookie: Option<String>,
+
+    /// compress responses the backend sent uncompressed when the client
+    /// accepts it
+    #[arg(long)]
+    compress: bool,
+
+    /// content coding for `--compress`, repeat it to offer several; of those
+    /// the client accepts, the one it prefers wins, and the first given on a
+    /// tie
+    #[arg(
+        long = "compress-algo",
+        value_enum,
+        default_value = "gzip",
+        requires = "compress"
+    )]
+    #[serde(rename = "compress-algo")]
+    compress_algos: Vec<CompressAlgo>,
+
+    /// compression level from 1 (fastest) to 9 (smallest), also brotli's
+    /// quality; 6 for gzip and 5 for brotli by default
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=9), requires = "compress")]
+    compress_level: Option<u32>,
+
+    /// leave responses of fewer bytes than this uncompressed; those of
+    /// unknown length always are compressed
+    #[arg(long, requires = "compress")]
+    compress_min_bytes: Option<u64>,
+
+    /// keep up to this many bytes of `public` GET responses with a `max-age`
+    /// and a known length in memory, serving them until they expire
+    #[arg(long)]
+    cache_size_bytes: Option<usize>,
+
+    /// route a subdomain to its own backends, e.g. `api=127.0.0.1:3000` or
+    /// `web=10.0.0.1:80,10.0.0.2:80@3` to balance with weights; the `*`
+    /// subdomain catches everything without a route of its own, and
+    /// `;timeout=<ms>` or `;max-body=<bytes>` after the targets override
+    /// `--request-timeout-ms` and `--max-body-bytes` for the route, and
+    /// `;methods=GET,HEAD` answers other methods with 405; `app=unix:<path>`
+    /// sends the requests to a local server on a Unix socket instead
+    #[arg(long = "route", value_parser = parse_route)]
+    #[serde(rename = "route", with = "config::route_list")]
+    routes: Vec<Route>,
+
+    /// send requests whose `content-type` (or, without one, `accept`) is
+    /// this media type to their own backend, e.g.
+    /// `application/grpc=127.0.0.1:50051`, which also takes
+    /// `application/grpc+proto`; `text/*` takes every text type; subdomain
+    /// routes other than `*` come first
+    #[arg(long = "content-route", value_parser = content_route_arg)]
+    #[serde(rename = "content-route")]
+    content_routes: Vec<String>,
+
+    /// send a share of the requests for a subdomain to a canary instead of
+    /// its usual backend, drawn at random for each request, e.g.
+    /// `web=10.0.0.2:80@10%`
+    #[arg(long = "canary", value_parser = canary_arg)]
+    #[serde(rename = "canary")]
+    canaries: Vec<String>,
+
+    /// tell clients whether a subdomain with a `--canary` was served by it
+    /// with an `x-canary: true` or `false` header
+    #[arg(long)]
+    canary_header: bool,
 }
 
-fn extract_domain(s: &str) -> Option<String> {
-    static XP: Lazy<Regex> = Lazy::new(|| {
-        Regex::new(
-            r"^(?<domain>([a-zA-Z0-9][a-zA-Z0-9-]*[a-zA-Z0-9]*\.)+)([0-9]{1,3}\.){4}nip\.io(:[0-9]+)?$",
-        )
-        .unwrap()
-    });
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected <name>:<value>, got {:?}", s))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name {:?}", name))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid header value {:?}", value))?;
+    Ok((name, value))
+}
 
-    XP.captures(s).map(|r| String::from(&r["domain"]))
+/// how the host header is rewritten for the backend
+#[derive(Debug, Clone, PartialEq)]
+enum HostRewrite {
+    Suffix,
+    Preserve,
+    Fixed(HeaderValue),
 }
 
-async fn proxy(
-    mut req: Request<hyper::body::Incoming>,
-    args: Args,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    let host = extract_domain(req.headers()["host"].to_str().unwrap()).unwrap();
-    let host = format!("{}{}", host, args.domain_suffix);
+fn parse_host_rewrite(s: &str) -> Result<HostRewrite, String> {
+    match s {
+        "suffix" => Ok(HostRewrite::Suffix),
+        "preserve" => Ok(HostRewrite::Preserve),
+        _ => match s.strip_prefix("fixed:") {
+            Some(host) => HeaderValue::from_str(host)
+                .map(HostRewrite::Fixed)
+                .map_err(|_| format!("invalid host {:?}", host)),
+            None => Err(format!(
+                "expected suffix, preserve or fixed:<host>, got {:?}",
+                s
+            )),
+        },
+    }
+}
 
-    info!("connecting to {}", host);
-    info!("headers: {:?}", req.headers());
+/// validates `--host-rewrite`, which is kept as given for the config file
+fn host_rewrite_arg(s: &str) -> Result<String, String> {
+    parse_host_rewrite(s).map(|_| s.to_string())
+}
 
-    req.headers_mut().remove("host");
-    req.headers_mut()
-        .insert("host", host.parse().expect("host.parse() failed"));
+fn ip_separator_arg(s: &str) -> Result<char, String> {
+    match s {
+        "." | "-" | "_" => Ok(s.chars().next().unwrap()),
+        _ => Err(format!("expected one of `.`, `-` or `_`, got {:?}", s)),
+    }
+}
 
-    let stream = TcpStream::connect((args.backend_host, args.backend_port))
-        .await
-        .unwrap();
+/// `<match>=<rewrite>`, the match with the trailing dot the subdomain has
+/// while being rewritten
+fn parse_suffix_map(s: &str) -> Result<(String, String), String> {
+    let (suffix, rewrite) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <match>=<rewrite>, got {:?}", s))?;
+    if suffix.is_empty() || subdomain_arg(suffix).is_err() {
+        return Err(format!("invalid subdomain suffix {:?}", suffix));
+    }
+    if rewrite.is_empty() || HeaderValue::from_str(rewrite).is_err() {
+        return Err(format!("invalid rewritten suffix {:?}", rewrite));
+    }
+    Ok((
+        format!("{}.", suffix.to_ascii_lowercase()),
+        rewrite.to_string(),
+    ))
+}
 
-    let io = tokio_io::TokioIo::new(stream);
+/// validates a `match=rewrite` flag, which is kept as given for the config
+/// file
+fn suffix_map_arg(s: &str) -> Result<String, String> {
+    parse_suffix_map(s).map(|_| s.to_string())
+}
 
-    let request_upgrade_type = get_upgrade_type(req.headers());
-    let request_upgraded = req.extensions_mut().remove::<OnUpgrade>();
+/// the part of `subdomain` (with its trailing dot) that is kept and the
+/// suffix it gets, from the longest matching entry of `suffix_map` or else
+/// `default`
+fn map_suffix<'a>(
+    subdomain: &'a str,
+    suffix_map: &'a [(String, String)],
+    default: &'a str,
+) -> (&'a str, &'a str) {
+    suffix_map
+        .iter()
+        .filter_map(|(suffix, rewrite)| {
+            let rest = subdomain.strip_suffix(suffix.as_str())?;
+            (rest.is_empty() || rest.ends_with('.')).then_some((suffix.len(), rest, rewrite))
+        })
+        .max_by_key(|(len, _, _)| *len)
+        .map_or((subdomain, default), |(_, rest, rewrite)| {
+            (rest, rewrite.as_str())
+        })
+}
 
-    let (mut sender, conn) = Builder::new()
-        .preserve_header_case(true)
-        .title_case_headers(true)
-        .handshake(io)
-        .await?;
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.with_upgrades().await {
-            println!("Connection failed: {:?}", err);
-        }
+/// `--default-subdomain` has to be something `extract_domain` could have
+/// returned
+fn subdomain_arg(s: &str) -> Result<String, String> {
+    let valid = s.split('.').all(|label| {
+        !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
     });
+    if s.is_empty() || valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("invalid subdomain {:?}", s))
+    }
+}
 
-    let mut resp = sender.send_request(req).await?;
+/// a rate a token bucket can refill at, one that is finite and above zero
+fn check_rate_limit(rate: f64) -> Result<f64, String> {
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(format!("rate limit has to be above zero, got {}", rate))
+    }
+}
 
-    if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
-        let response_upgrade_type = get_upgrade_type(resp.headers());
+fn rate_limit_arg(s: &str) -> Result<f64, String> {
+    let rate = s
+        .parse()
+        .map_err(|_| format!("invalid rate limit {:?}", s))?;
+    check_rate_limit(rate)
+}
 
-        if request_upgrade_type == response_upgrade_type {
-            if let Some(request_upgraded) = request_upgraded {
-                let response_upgraded = resp
-                    .extensions_mut()
-                    .remove::<OnUpgrade>()
-                    .expect("response does not have an upgrade extension")
-                    .await?;
+fn cookie_name_arg(s: &str) -> Result<String, String> {
+    let valid = !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("invalid cookie name {:?}", s))
+    }
+}
 
-                debug!("Responding to a connection upgrade response");
+/// the `--allow-subdomain` patterns, which have to match the whole subdomain
+fn subdomain_allowlist(patterns: &[String]) -> Result<Option<RegexSet>, regex::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    RegexSet::new(patterns.iter().map(|pattern| format!("^(?:{})$", pattern))).map(Some)
+}
 
-                tokio::spawn(async move {
-                    let request_upgraded =
-                        request_upgraded.await.expect("failed to upgrade request");
+fn allow_subdomain_arg(s: &str) -> Result<String, String> {
+    subdomain_allowlist(&[s.to_string()])
+        .map(|_| s.to_string())
+        .map_err(|e| e.to_string())
+}
 
-                    let mut a = tokio_io::TokioIo::new(response_upgraded);
-                    let mut b = tokio_io::TokioIo::new(request_upgraded);
+/// header name flags where an empty value turns the header off
+fn optional_header_name(name: &str) -> Result<Option<HeaderName>, InvalidHeaderName> {
+    match name {
+        "" => Ok(None),
+        name => HeaderName::from_bytes(name.as_bytes()).map(Some),
+    }
+}
 
-                    copy_bidirectional(&mut a, &mut b)
-                        .await
-                        .expect("coping between upgraded connections failed");
-                });
+fn parse_header_name(s: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(s.trim().as_bytes()).map_err(|_| format!("invalid header name {:?}", s))
+}
 
-                // Ok(resp)
-            } else {
-                error!("request does not have an upgrade extension")
-            }
-        } else {
-            error!(
-                "backend tried to switch to protocol {:?} when {:?} was requested",
-                response_upgrade_type, request_upgrade_type
-            )
-        }
+fn header_name_arg(s: &str) -> Result<String, String> {
+    parse_header_name(s).map(|_| s.to_string())
+}
+
+/// a header the client sent that the backend does not get; the host header
+/// is the proxy's to set
+fn parse_remove_request_header(s: &str) -> Result<HeaderName, String> {
+    match parse_header_name(s)? {
+        name if name == HOST => Err("the host header cannot be removed".to_string()),
+        name => Ok(name),
+    }
+}
+
+fn remove_request_header_arg(s: &str) -> Result<String, String> {
+    parse_remove_request_header(s).map(|_| s.to_string())
+}
+
+/// validates a `name:value` flag, which is kept as given for the config file
+fn header_arg(s: &str) -> Result<String, String> {
+    parse_header(s).map(|_| s.to_string())
+}
+
+fn parse_status_rewrite(s: &str) -> Result<(StatusCode, StatusCode), String> {
+    let (from, to) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected <from>:<to>, got {:?}", s))?;
+    let status = |code: &str| {
+        code.trim()
+            .parse::<u16>()
+            .ok()
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .filter(|status| !status.is_informational())
+            .ok_or_else(|| format!("invalid status {:?}", code))
+    };
+    Ok((status(from)?, status(to)?))
+}
+
+/// validates a `from:to` flag, which is kept as given for the config file
+fn status_rewrite_arg(s: &str) -> Result<String, String> {
+    parse_status_rewrite(s).map(|_| s.to_string())
+}
+
+/// a `<subdomain>=<host:port>@<percent>%` flag
+fn parse_canary(s: &str) -> Result<(String, SocketAddr, f64), String> {
+    let expected = || format!("expected <subdomain>=<host:port>@<percent>%, got {:?}", s);
+    let (subdomain, target) = s.split_once('=').ok_or_else(expected)?;
+    // hosts are lowercased before their subdomain is looked up
+    let valid = !subdomain.is_empty()
+        && subdomain.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        });
+    if !valid {
+        return Err(format!("invalid canary subdomain {:?}", subdomain));
     }
+    let (target, percent) = target.rsplit_once('@').ok_or_else(expected)?;
+    let percent = percent
+        .strip_suffix('%')
+        .unwrap_or(percent)
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| format!("invalid canary percentage {:?}", percent))?;
+    let addr = target
+        .to_socket_addrs()
+        .map_err(|e| format!("invalid canary target {:?}: {}", target, e))?
+        .next()
+        .ok_or_else(|| format!("canary target {:?} did not resolve", target))?;
+    Ok((subdomain.to_string(), addr, percent))
+}
 
-    Ok(resp.map(|b| b.boxed()))
+/// validates a `--canary`, which is kept as given for the config file
+fn canary_arg(s: &str) -> Result<String, String> {
+    parse_canary(s).map(|_| s.to_string())
 }
 
-fn get_upgrade_type(headers: &HeaderMap) -> Option<String> {
-    #[allow(clippy::blocks_in_if_conditions)]
-    if headers
-        .get("connection")
-        .map(|value| {
-            value
-                .to_str()
-                .unwrap()
-                .split(',')
-                .any(|e| e.trim().to_lowercase() == "upgrade")
-        })
-        .unwrap_or(false)
-    {
-        if let Some(upgrade_value) = headers.get("upgrade") {
-            debug!(
-                "Found upgrade header with value: {}",
-                upgrade_value.to_str().unwrap().to_owned()
-            );
+/// a signal given by name, with or without `SIG`, or by number
+#[cfg(unix)]
+fn parse_signal(s: &str) -> Result<SignalKind, String> {
+    let name = s.to_ascii_uppercase();
+    let kind = match name.strip_prefix("SIG").unwrap_or(&name) {
+        "ALRM" => SignalKind::alarm(),
+        "CHLD" => SignalKind::child(),
+        "HUP" => SignalKind::hangup(),
+        "INT" => SignalKind::interrupt(),
+        "IO" => SignalKind::io(),
+        "PIPE" => SignalKind::pipe(),
+        "QUIT" => SignalKind::quit(),
+        "TERM" => SignalKind::terminate(),
+        "USR1" => SignalKind::user_defined1(),
+        "USR2" => SignalKind::user_defined2(),
+        "WINCH" => SignalKind::window_change(),
+        number => match number.parse() {
+            Ok(number) if number > 0 => SignalKind::from_raw(number),
+            _ => return Err(format!("unknown signal {:?}", s)),
+        },
+    };
+    Ok(kind)
+}
+
+/// validates a `--shutdown-signal`, which is kept as given for the config
+/// file
+#[cfg(unix)]
+fn shutdown_signal_arg(s: &str) -> Result<String, String> {
+    parse_signal(s).map(|_| s.to_string())
+}
 
-            return Some(upgrade_value.to_str().unwrap().to_owned());
+/// a `<media type>=<host:port>` flag, the media type lowercased
+fn parse_content_route(s: &str) -> Result<(String, Backend), String> {
+    let (media_type, backend) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <content-type>=<host:port>, got {:?}", s))?;
+    let media_type = media_type.trim().to_ascii_lowercase();
+    let valid = media_type
+        .split_once('/')
+        .is_some_and(|(kind, subtype)| !kind.is_empty() && kind != "*" && !subtype.is_empty());
+    if !valid {
+        return Err(format!("invalid media type {:?}", media_type));
+    }
+    let backend = match backend.parse::<SocketAddr>() {
+        Ok(addr) => Backend::Addr(addr),
+        Err(_) => {
+            let (host, port) = backend
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+                .filter(|(host, _)| !host.is_empty())
+                .ok_or_else(|| format!("expected <host:port>, got {:?}", backend))?;
+            Backend::Host(host.to_string(), port)
         }
+    };
+    Ok((media_type, backend))
+}
+
+/// validates a `--content-route`, which is kept as given for the config
+/// file
+fn content_route_arg(s: &str) -> Result<String, String> {
+    parse_content_route(s).map(|_| s.to_string())
+}
+
+/// the backend of the first of `routes` matching the media type in
+/// `content-type`, or else one of those in `accept`
+fn content_route<'a>(headers: &HeaderMap, routes: &'a [(String, Backend)]) -> Option<&'a Backend> {
+    if routes.is_empty() {
+        return None;
     }
+    let media_types = |name: HeaderName| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|media_type| media_type.split(';').next())
+            .map(|media_type| media_type.trim().to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    };
+    let matches = |route: &str, media_type: &str| match route.strip_suffix("/*") {
+        Some(kind) => media_type
+            .strip_prefix(kind)
+            .is_some_and(|rest| rest.starts_with('/')),
+        None => media_type
+            .strip_prefix(route)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('+')),
+    };
+    let mut offered = media_types(CONTENT_TYPE);
+    if offered.is_empty() {
+        offered = media_types(ACCEPT);
+    }
+    routes.iter().find_map(|(route, backend)| {
+        offered
+            .iter()
+            .any(|media_type| matches(route, media_type))
+            .then_some(backend)
+    })
+}
 
-    None
+/// a `--trusted-proxies` network, a bare address being one of its own
+fn parse_trusted_proxy(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid network {:?}", s))
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
+/// validates a `--trusted-proxies` network, which is kept as given for the
+/// config file
+fn trusted_proxy_arg(s: &str) -> Result<String, String> {
+    parse_trusted_proxy(s).map(|_| s.to_string())
+}
 
-    let args = Args::parse();
+/// headers given as repeated `name:value` flags
+fn parse_headers(headers: &[String]) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let (name, value) = parse_header(header)?;
+        map.append(name, value);
+    }
+    Ok(map)
+}
 
-    let addr = SocketAddr::from((
-        Ipv4Addr::from_str(&args.proxy_host).expect("invalid ip v4 addr"),
-        args.proxy_port,
-    ));
+/// replace the values of every header in `headers`
+fn set_headers(target: &mut HeaderMap, headers: &HeaderMap) {
+    for name in headers.keys() {
+        target.remove(name);
+        for value in headers.get_all(name) {
+            target.append(name, value.clone());
+        }
+    }
+}
+
+fn parse_route(s: &str) -> Result<Route, String> {
+    let (subdomain, targets) = s.split_once('=').ok_or_else(|| {
+        format!(
+            "expected <subdomain>=<host>:<port>[@<weight>][,...][;<limit>=<value>...] or <subdomain>=unix:<path>, got {:?}",
+            s
+        )
+    })?;
+    let mut options = targets.split(';');
+    let targets = options.next().expect("split yields at least one part");
+    let mut limits = Limits::default();
+    for option in options {
+        match option.split_once('=') {
+            Some(("timeout", ms)) => {
+                limits.timeout_ms = Some(
+                    ms.parse()
+                        .map_err(|_| format!("invalid route timeout {:?}", ms))?,
+                )
+            }
+            Some(("max-body", bytes)) => {
+                limits.max_body_bytes = Some(
+                    bytes
+                        .parse()
+                        .map_err(|_| format!("invalid route body limit {:?}", bytes))?,
+                )
+            }
+            Some(("methods", methods)) => {
+                limits.methods = Some(
+                    methods
+                        .split(',')
+                        .map(|method| {
+                            Method::from_bytes(method.as_bytes())
+                                .map_err(|_| format!("invalid route method {:?}", method))
+                        })
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            _ => {
+                return Err(format!(
+                    "expected timeout=<ms>, max-body=<bytes> or methods=<method>[,...], got {:?}",
+                    option
+                ))
+            }
+        }
+    }
+    if let Some(path) = targets.strip_prefix("unix:") {
+        if cfg!(not(unix)) {
+            return Err("Unix socket route targets are not supported here".to_string());
+        }
+        if path.is_empty() {
+            return Err(format!("missing socket path in route {:?}", s));
+        }
+        return Ok(Route {
+            subdomain: subdomain.to_string(),
+            targets: Vec::new(),
+            socket: Some(PathBuf::from(path)),
+            limits,
+        });
+    }
+    let targets = targets
+        .split(',')
+        .map(|target| {
+            let (target, weight) = match target.rsplit_once('@') {
+                Some((target, weight)) => match weight.parse() {
+                    Ok(weight) if weight > 0 => (target, weight),
+                    _ => return Err(format!("invalid route weight {:?}", weight)),
+                },
+                None => (target, 1),
+            };
+            let addr = target
+                .to_socket_addrs()
+                .map_err(|e| format!("invalid route target {:?}: {}", target, e))?
+                .next()
+                .ok_or_else(|| format!("route target {:?} did not resolve", target))?;
+            Ok(Target { addr, weight })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(Route {
+        subdomain: subdomain.to_string(),
+        targets,
+        socket: None,
+        limits,
+    })
+}
 
-    let listener = TcpListener::bind(addr).await?;
-    info!("Listening on http://{}", addr);
+struct State {
+    args: Args,
+    domain_regex: Regex,
+    allowed_subdomains: Option<RegexSet>,
+    /// balancer and limits of each routed subdomain
+    routes: HashMap<String, (Upstream, Limits)>,
+    tls: Option<TlsAcceptor>,
+    backend_tls: Option<TlsConnector>,
+    pool: Arc<Pool<BoxBody<Bytes, BoxError>>>,
+    rate_limiter: Option<RateLimiter>,
+    breaker: Option<CircuitBreaker>,
+    basic_auth: Option<BasicAuth>,
+    cors: Option<Cors>,
+    cache: Option<Cache>,
+    host_rewrite: HostRewrite,
+    request_headers: HeaderMap,
+    response_headers: HeaderMap,
+    remove_request_headers: Vec<HeaderName>,
+    remove_response_headers: Vec<HeaderName>,
+    request_id_header: Option<HeaderName>,
+    subdomain_header: Option<HeaderName>,
+    socket_options: SocketOptions,
+    error_pages: HashMap<StatusCode, Bytes>,
+    suffix_map: Vec<(String, String)>,
+    content_routes: Vec<(String, Backend)>,
+    /// canary target and the percentage of requests it gets, by subdomain
+    canaries: HashMap<String, (SocketAddr, f64)>,
+    trusted_proxies: Vec<IpNet>,
+    status_rewrites: HashMap<StatusCode, StatusCode>,
+    health: HealthMap,
+    metrics: Arc<Metrics>,
+    maintenance: AtomicBool,
+}
 
-    let mut sig_int = signal(SignalKind::interrupt()).unwrap();
-    let mut sig_term = signal(SignalKind::terminate()).unwrap();
-    tokio::select! {
-        _ = async {
-            loop {
-                let (stream, _) = match listener.accept().await {
-                    Ok(sock) => sock,
-                    Err(e) => {
-                        error!("Error when accepting {:?}", e);
-                        break;
-                    }
+impl State {
+    /// state for reloaded `args`, keeping the connection pool, the health
+    /// check results and the metrics; circuit breakers and rate limits start
+    /// over, and maintenance mode stays as SIGUSR1 left it unless the new
+    /// configuration turns `maintenance` on or off
+    fn reload(&self, args: Args) -> Result<Self, Box<dyn std::error::Error>> {
+        let maintenance = if args.maintenance == self.args.maintenance {
+            self.maintenance.load(Ordering::Relaxed)
+        } else {
+            args.maintenance
+        };
+        Ok(Self {
+            pool: self.pool.clone(),
+            health: self.health.clone(),
+            metrics: self.metrics.clone(),
+            maintenance: AtomicBool::new(maintenance),
+            ..Self::new(args)?
+        })
+    }
+
+    fn new(args: Args) -> Result<Self, Box<dyn std::error::Error>> {
+        // the command line checks these too, but not the config file
+        if args
+            .max_header_bytes
+            .is_some_and(|max| max < MIN_HEADER_BYTES)
+        {
+            return Err(format!("max-header-bytes must be at least {}", MIN_HEADER_BYTES).into());
+        }
+        if let Some(default) = &args.default_subdomain {
+            subdomain_arg(default)?;
+        }
+        if let Some(name) = &args.sticky_cookie {
+            cookie_name_arg(name)?;
+        }
+        if let Some(rate) = args.rate_limit {
+            check_rate_limit(rate)?;
+        }
+        for separator in &args.ip_separators {
+            ip_separator_arg(&separator.to_string())?;
+        }
+        #[cfg(unix)]
+        for signal in &args.shutdown_signals {
+            shutdown_signal_arg(signal)?;
+        }
+        if args
+            .compress_level
+            .is_some_and(|level| !(1..=9).contains(&level))
+        {
+            return Err("--compress-level has to be from 1 to 9".into());
+        }
+        let domain_regex = domain_regex(&args.wildcard_suffixes, &args.ip_separators);
+        // repeated routes for a subdomain add up to one target group, limits
+        // given later taking precedence; a socket is the only target
+        let mut targets = HashMap::<_, (Vec<_>, Option<PathBuf>, Limits)>::new();
+        for route in &args.routes {
+            let (targets, socket, limits) = targets.entry(route.subdomain.clone()).or_default();
+            targets.extend_from_slice(&route.targets);
+            if route.socket.is_some() {
+                *socket = route.socket.clone();
+            }
+            if socket.is_some() && !targets.is_empty() {
+                return Err(format!(
+                    "route {:?} mixes a Unix socket with other targets",
+                    route.subdomain
+                )
+                .into());
+            }
+            // neither has anything to go on with a local socket
+            if route.socket.is_some() && (args.backend_tls || args.send_proxy_protocol) {
+                return Err(format!(
+                    "route {:?} is a Unix socket, which --backend-tls and --send-proxy-protocol do not work with",
+                    route.subdomain
+                )
+                .into());
+            }
+            *limits = limits.clone().or(route.limits.clone());
+        }
+        let routes = targets
+            .into_iter()
+            .map(|(subdomain, (targets, socket, limits))| {
+                let upstream = match socket {
+                    #[cfg(unix)]
+                    Some(path) => Upstream::Unix(path),
+                    _ => Upstream::Balancer(Balancer::new(targets)),
                 };
+                (subdomain, (upstream, limits))
+            })
+            .collect();
+        let tls = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::load_acceptor(cert, key, args.http2)?),
+            _ => None,
+        };
+        let pool = Arc::new(Pool::new(
+            args.max_idle_per_host,
+            args.max_connections_per_host,
+        ));
+        let rate_limiter = args.rate_limit.map(RateLimiter::new);
+        let breaker = args.breaker_threshold.map(|threshold| {
+            CircuitBreaker::new(threshold, Duration::from_millis(args.breaker_cooldown_ms))
+        });
+        let basic_auth = (!args.basic_auth.is_empty()).then(|| BasicAuth::new(&args.basic_auth));
+        let backend_tls = args
+            .backend_tls
+            .then(|| tls::backend_connector(args.backend_tls_insecure));
+        let allowed_subdomains = subdomain_allowlist(&args.allow_subdomains)?;
+        let cors = match args.cors_allow_origins.as_slice() {
+            [] => None,
+            origins => Some(Cors::new(origins)?),
+        };
+        let cache = args.cache_size_bytes.map(Cache::new);
+        let maintenance = AtomicBool::new(args.maintenance);
+        let host_rewrite = parse_host_rewrite(&args.host_rewrite)?;
+        let request_headers = parse_headers(&args.add_request_headers)?;
+        let response_headers = parse_headers(&args.add_response_headers)?;
+        let remove_request_headers = args
+            .remove_request_headers
+            .iter()
+            .map(|name| parse_remove_request_header(name))
+            .collect::<Result<_, _>>()?;
+        let remove_response_headers = args
+            .remove_response_headers
+            .iter()
+            .map(|name| parse_header_name(name))
+            .collect::<Result<_, _>>()?;
+        let request_id_header = optional_header_name(&args.request_id_header)?;
+        let subdomain_header = optional_header_name(&args.subdomain_header)?;
+        let error_pages = match &args.error_page_dir {
+            Some(dir) => load_error_pages(dir)?,
+            None => HashMap::new(),
+        };
+        let socket_options = SocketOptions {
+            nodelay: args.tcp_nodelay,
+            keepalive: args.tcp_keepalive_ms.map(Duration::from_millis),
+        };
+        let suffix_map = args
+            .suffix_maps
+            .iter()
+            .map(|entry| parse_suffix_map(entry))
+            .collect::<Result<_, _>>()?;
+        let content_routes = args
+            .content_routes
+            .iter()
+            .map(|route| parse_content_route(route))
+            .collect::<Result<_, _>>()?;
+        let canaries = args
+            .canaries
+            .iter()
+            .map(|canary| {
+                parse_canary(canary).map(|(subdomain, addr, percent)| (subdomain, (addr, percent)))
+            })
+            .collect::<Result<_, _>>()?;
+        let trusted_proxies = args
+            .trusted_proxies
+            .iter()
+            .map(|network| parse_trusted_proxy(network))
+            .collect::<Result<_, _>>()?;
+        let status_rewrites = args
+            .status_rewrites
+            .iter()
+            .map(|rewrite| parse_status_rewrite(rewrite))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            args,
+            domain_regex,
+            allowed_subdomains,
+            routes,
+            tls,
+            backend_tls,
+            pool,
+            rate_limiter,
+            breaker,
+            basic_auth,
+            cors,
+            cache,
+            host_rewrite,
+            request_headers,
+            response_headers,
+            remove_request_headers,
+            remove_response_headers,
+            request_id_header,
+            subdomain_header,
+            socket_options,
+            error_pages,
+            suffix_map,
+            content_routes,
+            canaries,
+            trusted_proxies,
+            status_rewrites,
+            health: HealthMap::default(),
+            metrics: Arc::default(),
+            maintenance,
+        })
+    }
+}
 
-                let args = args.clone();
-                let io = tokio_io::TokioIo::new(stream);
+/// the state new connections and requests are served with, replaced as a
+/// whole when the configuration is reloaded
+#[derive(Clone)]
+struct SharedState(Arc<RwLock<Arc<State>>>);
 
-                tokio::task::spawn(async move {
-                    let service = service_fn( move |req| {
-                        let args = args.clone();
-                        proxy(req, args)
-                });
+impl SharedState {
+    fn new(state: State) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(state))))
+    }
 
-                    if let Err(err) = http1::Builder::new()
-                        .preserve_header_case(true)
-                        .title_case_headers(true)
-                        .serve_connection(io, service).with_upgrades()
-                        .await
-                    {
-                        println!("Failed to serve connection: {:?}", err);
-                    }
-                });
-            }
-            Ok::<(), Box<dyn std::error::Error>>(())
-        } => {},
-        _ = sig_int.recv() => debug!("SIGINT received"),
-        _ = sig_term.recv() => debug!("SIGTERM received"),
-        _ = ctrl_c() => debug!("'Ctrl C' received"),
+    fn current(&self) -> Arc<State> {
+        self.0.read().unwrap().clone()
     }
 
-    Ok(())
+    fn replace(&self, state: State) {
+        *self.0.write().unwrap() = Arc::new(state);
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    #[test]
+/// transport level information about the connected client
+#[derive(Debug, Clone)]
+struct Client {
+    /// unset for clients connected through a Unix socket
+    addr: Option<SocketAddr>,
+    tls: bool,
+    /// server name the client asked for in the TLS handshake
+    sni: Option<String>,
+    /// address from a trusted `forwarded` header
+    forwarded: Option<IpAddr>,
+    /// the `--queue-depth` queue had no room for the connection
+    queue_full: bool,
+}
+
+impl Client {
+    /// the address requests are attributed to
+    fn ip(&self) -> Option<IpAddr> {
+        self.forwarded.or(self.addr.map(|addr| addr.ip()))
+    }
+}
+
+/// what `forward` settled on for a request, for the access log
+#[derive(Default)]
+struct Selected {
+    backend: Option<Backend>,
+    /// whether the canary was picked, for subdomains that have one
+    canary: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+enum Backend {
+    Addr(SocketAddr),
+    Host(String, u16),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Backend {
+    fn server_name(&self) -> Result<ServerName<'static>, InvalidDnsNameError> {
+        match self {
+            Backend::Addr(addr) => Ok(ServerName::IpAddress(addr.ip().into())),
+            Backend::Host(host, _) => ServerName::try_from(host.clone()),
+            // never asked for, as TLS is not spoken over a local socket
+            #[cfg(unix)]
+            Backend::Unix(_) => ServerName::try_from("localhost"),
+        }
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Addr(addr) => write!(f, "{}", addr),
+            Backend::Host(host, port) => write!(f, "{}:{}", host, port),
+            #[cfg(unix)]
+            Backend::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// regex matching `<sub>.<ip>.<suffix>` hosts for any of the wildcard DNS
+/// suffixes, where `<ip>` is either dotted IPv4 or the dashed IPv6 form
+/// (`2001-db8--1`) used by sslip.io
+/// `separators` are the ones allowed between the numbers of an IPv4 address,
+/// one of them throughout the address
+fn domain_regex(suffixes: &[String], separators: &[char]) -> Regex {
+    let suffixes = suffixes
+        .iter()
+        .map(|suffix| regex::escape(&suffix.to_ascii_lowercase()))
+        .collect::<Vec<_>>()
+        .join("|");
+    let ipv4 = separators
+        .iter()
+        .map(|separator| {
+            format!(
+                "[0-9]{{1,3}}({}[0-9]{{1,3}}){{3}}",
+                regex::escape(&separator.to_string())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("|");
+    Regex::new(&format!(
+        r"^(?<domain>([a-zA-Z0-9][a-zA-Z0-9-]*[a-zA-Z0-9]*\.)*)((?<ipv4>{})\.|(?<ipv6>[a-zA-Z0-9-]*-[a-zA-Z0-9-]*)\.)({})(:[0-9]+)?$",
+        ipv4, suffixes
+    ))
+    .unwrap()
+}
+
+fn extract_domain(xp: &Regex, s: &str) -> Option<String> {
+    extract_subdomain(xp, s)
+        .map(|(domain, _)| domain)
+        .filter(|domain| !domain.is_empty())
+}
+
+/// like `extract_domain`, but an empty string for hosts without a subdomain,
+/// e.g. `192.168.1.1.nip.io`, together with the embedded address unless it is
+/// out of range, as in `foo.1.2.3.999.nip.io`
+fn extract_subdomain(xp: &Regex, s: &str) -> Option<(String, Option<IpAddr>)> {
+    let s = normalize_host(s);
+    // longer names cannot be in DNS, and would make the search for the
+    // address in an IPv6 label below take quadratic time
+    let name = s.split(':').next().unwrap_or_default();
+    if name.len() > MAX_HOST_LEN || name.split('.').any(|label| label.len() > MAX_LABEL_LEN) {
+        return None;
+    }
+    let captures = xp.captures(&s)?;
+    let mut domain = String::from(&captures["domain"]);
+    let mut ip = captures
+        .name("ipv4")
+        .and_then(|ipv4| {
+            ipv4.as_str()
+                .replace(['-', '_'], ".")
+                .parse::<Ipv4Addr>()
+                .ok()
+        })
+        .map(IpAddr::from);
+
+    // the IPv6 label may carry the innermost subdomain in front of the
+    // address, as in `foo-2001-db8--1`
+    if let Some(label) = captures.name("ipv6") {
+        let label = label.as_str();
+        let address = if is_dashed_ipv6(label) {
+            label
+        } else {
+            let prefix = label
+                .match_indices('-')
+                .map(|(i, _)| &label[..i])
+                .find(|prefix| {
+                    !prefix.ends_with('-') && is_dashed_ipv6(&label[prefix.len() + 1..])
+                })?;
+            domain.push_str(prefix);
+            domain.push('.');
+            &label[prefix.len() + 1..]
+        };
+        ip = address
+            .replace('-', ":")
+            .parse::<Ipv6Addr>()
+            .ok()
+            .map(IpAddr::from);
+    }
+
+    Some((domain, ip))
+}
+
+/// loopback, private, link-local and other addresses not reachable on the
+/// internet, which `--deny-private` keeps the proxy from connecting to
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+/// hosts are case-insensitive and may end in the dot of the DNS root, as in
+/// `FOO.192.168.1.1.NIP.IO.:8080`
+fn normalize_host(host: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            (name, Some(port))
+        }
+        _ => (host.as_str(), None),
+    };
+    let name = name.strip_suffix('.').unwrap_or(name);
+    match port {
+        Some(port) => format!("{}:{}", name, port),
+        None => name.to_string(),
+    }
+}
+
+fn is_dashed_ipv6(s: &str) -> bool {
+    s.contains('-') && s.replace('-', ":").parse::<Ipv6Addr>().is_ok()
+}
+
+fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, BoxError> {
+    Full::new(chunk.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+/// marks responses the proxy made up itself rather than got from a backend
+#[derive(Debug, Clone, Copy)]
+struct ProxyError;
+
+fn error_response(status: StatusCode, message: &'static str) -> Response<BoxBody<Bytes, BoxError>> {
+    let mut resp = Response::new(full(message));
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert("content-type", "text/plain; charset=utf-8".parse().unwrap());
+    resp.extensions_mut().insert(ProxyError);
+    resp
+}
+
+/// why the backend could not be reached, without addresses or anything
+/// else from its error messages beyond their kind
+#[derive(Debug, Clone)]
+struct ErrorDetail(String);
+
+/// an error response for a backend failure, with `detail` for
+/// `--expose-error-detail`
+fn gateway_error(
+    status: StatusCode,
+    message: &'static str,
+    detail: impl Into<String>,
+) -> Response<BoxBody<Bytes, BoxError>> {
+    let mut resp = error_response(status, message);
+    resp.extensions_mut().insert(ErrorDetail(detail.into()));
+    resp
+}
+
+fn maintenance_response(args: &Args) -> Response<BoxBody<Bytes, BoxError>> {
+    let mut resp = Response::new(full(args.maintenance_body.clone()));
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    resp.headers_mut()
+        .insert("content-type", "text/plain; charset=utf-8".parse().unwrap());
+    if let Some(seconds) = args.maintenance_retry_after {
+        resp.headers_mut()
+            .insert("retry-after", HeaderValue::from(seconds));
+    }
+    resp
+}
+
+/// the host the request is for, from the `host` header, which has to agree
+/// with the authority an absolute-form target (or HTTP/2's `:authority`)
+/// carries, or else from that authority; the error is the message for a 400
+fn request_host<B>(req: &Request<B>) -> Result<&str, &'static str> {
+    let target = req.uri().authority().map(|authority| {
+        let authority = authority.as_str();
+        authority
+            .rsplit_once('@')
+            .map_or(authority, |(_, host)| host)
+    });
+    match req.headers().get("host") {
+        Some(host) => {
+            let host = host
+                .to_str()
+                .map_err(|_| "host header is not valid ascii\n")?;
+            match target {
+                Some(target) if normalize_host(target) != normalize_host(host) => {
+                    Err("host header does not match the request target\n")
+                }
+                _ => Ok(host),
+            }
+        }
+        None => target.ok_or("missing host header\n"),
+    }
+}
+
+/// 301 to the https version of the request url, leaving out the port the
+/// plaintext request came in on
+fn https_redirect<B>(req: &Request<B>) -> Response<BoxBody<Bytes, BoxError>> {
+    let authority = request_host(req)
+        .ok()
+        .and_then(|host| host.parse::<Authority>().ok());
+    let Some(authority) = authority.filter(|authority: &Authority| !authority.host().is_empty())
+    else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid host header\n");
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let location = format!("https://{}{}", authority.host(), path);
+    let mut resp = Response::new(full(""));
+    *resp.status_mut() = StatusCode::MOVED_PERMANENTLY;
+    resp.headers_mut().insert(
+        LOCATION,
+        HeaderValue::from_str(&location).expect("location is built from header values"),
+    );
+    resp
+}
+
+/// `<status>.html` files from `dir`, e.g. `502.html`
+fn load_error_pages(dir: &Path) -> Result<HashMap<StatusCode, Bytes>, String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("failed to read {}: {}", dir.display(), e))?;
+    let mut pages = HashMap::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("failed to read {}: {}", dir.display(), e))?
+            .path();
+        let status = path
+            .extension()
+            .filter(|extension| *extension == "html")
+            .and_then(|_| path.file_stem()?.to_str()?.parse::<u16>().ok())
+            .and_then(|code| StatusCode::from_u16(code).ok());
+        if let Some(status) = status {
+            let page = std::fs::read(&path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            pages.insert(status, Bytes::from(page));
+        }
+    }
+    Ok(pages)
+}
+
+async fn proxy(
+    mut req: Request<hyper::body::Incoming>,
+    state: Arc<State>,
+    mut client: Client,
+) -> Result<Response<BoxBody<Bytes, BoxError>>, hyper::Error> {
+    let started = Instant::now();
+    if state.args.trust_forwarded {
+        client.forwarded = forwarded::client(req.headers());
+    }
+    let client_ip = client.ip().map(|ip| ip.to_string());
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let mut selected = Selected::default();
+    state.metrics.request_started();
+
+    // the client's request id is kept, otherwise a new one is made up
+    let origin = req.headers().get(ORIGIN).cloned();
+    // before the proxy's own headers go on; CORS still answers the origin
+    for name in &state.remove_request_headers {
+        req.headers_mut().remove(name);
+    }
+    let request_id = state.request_id_header.as_ref().map(|name| {
+        let id = req
+            .headers()
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap());
+        req.headers_mut().insert(name, id.clone());
+        (name, id)
+    });
+
+    let span = match &state.args.otlp_endpoint {
+        Some(_) => {
+            let span = info_span!(
+                "proxy",
+                otel.name = %method,
+                otel.kind = "server",
+                otel.status_code = field::Empty,
+                http.request.method = %method,
+                url.path = %path,
+                http.response.status_code = field::Empty,
+                backend = field::Empty,
+                elapsed_ms = field::Empty,
+            );
+            // a malformed traceparent just starts a new trace
+            let _ = span.set_parent(telemetry::extract(req.headers()));
+            telemetry::inject(&span.context(), req.headers_mut());
+            span
+        }
+        None => Span::none(),
+    };
+
+    let mut result = forward(req, state.clone(), client, &mut selected)
+        .instrument(span.clone())
+        .await;
+    if let Ok(resp) = &mut result {
+        // before the proxy's own headers go on
+        for name in &state.remove_response_headers {
+            resp.headers_mut().remove(name);
+        }
+        if resp.extensions().get::<ProxyError>().is_none() {
+            if let Some(&status) = state.status_rewrites.get(&resp.status()) {
+                *resp.status_mut() = status;
+                // gets the error page like the proxy's own errors
+                resp.extensions_mut().insert(ProxyError);
+            }
+        }
+        let gateway_failed = matches!(
+            resp.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT
+        );
+        if state.args.expose_error_detail && gateway_failed {
+            let detail = resp.extensions().get::<ErrorDetail>().cloned();
+            if let Some(Ok(detail)) = detail.map(|detail| HeaderValue::from_str(&detail.0)) {
+                resp.headers_mut().insert("x-proxy-error", detail);
+            }
+        }
+        if resp.extensions().get::<ProxyError>().is_some() {
+            if let Some(page) = state.error_pages.get(&resp.status()) {
+                *resp.body_mut() = full(page.clone());
+                resp.headers_mut().remove(CONTENT_LENGTH);
+                resp.headers_mut().remove(CONTENT_ENCODING);
+                resp.headers_mut().insert(
+                    "content-type",
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+            }
+        }
+        if let Some((name, id)) = &request_id {
+            resp.headers_mut().insert(*name, id.clone());
+        }
+        if let Some(cors) = &state.cors {
+            cors.apply(origin.as_ref(), resp.headers_mut());
+        }
+        if let Some(canary) = selected.canary.filter(|_| state.args.canary_header) {
+            resp.headers_mut().insert(
+                "x-canary",
+                HeaderValue::from_static(if canary { "true" } else { "false" }),
+            );
+        }
+        set_headers(resp.headers_mut(), &state.response_headers);
+    }
+    let request_id = request_id
+        .as_ref()
+        .map(|(_, id)| String::from_utf8_lossy(id.as_bytes()).into_owned());
+
+    let backend = selected.backend.as_ref().map(ToString::to_string);
+    let canary = selected.canary;
+    let elapsed = started.elapsed();
+    state.metrics.observe_latency(elapsed);
+    let elapsed_ms = elapsed.as_secs_f64() * 1000.0;
+    span.record("backend", backend.as_deref());
+    span.record("elapsed_ms", elapsed_ms);
+    match &result {
+        Ok(resp) => {
+            span.record("http.response.status_code", resp.status().as_u16());
+            if resp.status().is_server_error() {
+                span.record("otel.status_code", "error");
+            }
+        }
+        Err(_) => {
+            span.record("otel.status_code", "error");
+        }
+    }
+    match result {
+        Ok(resp) => {
+            state.metrics.response_sent(resp.status());
+            let status = resp.status().as_u16();
+            let metrics = state.metrics.clone();
+            // logged once the body is through, to tell slow backends from
+            // slow clients
+            let on_done: body::OnDone = Box::new(move |backend_wait, client_wait| {
+                metrics.observe_body_waits(backend_wait, client_wait);
+                info!(
+                    method = %method,
+                    path = %path,
+                    status,
+                    client = client_ip.as_deref(),
+                    backend = backend.as_deref(),
+                    canary,
+                    request_id = request_id.as_deref(),
+                    elapsed_ms,
+                    backend_wait_ms = backend_wait.as_secs_f64() * 1000.0,
+                    client_wait_ms = client_wait.as_secs_f64() * 1000.0,
+                    "request completed"
+                )
+            });
+            Ok(resp.map(|body| Timed::new(body, on_done).boxed()))
+        }
+        Err(err) => {
+            error!(
+                method = %method,
+                path = %path,
+                client = client_ip.as_deref(),
+                backend = backend.as_deref(),
+                canary,
+                request_id = request_id.as_deref(),
+                elapsed_ms,
+                error = %err,
+                "request failed"
+            );
+            Err(err)
+        }
+    }
+}
+
+async fn forward(
+    mut req: Request<hyper::body::Incoming>,
+    state: Arc<State>,
+    client: Client,
+    selected: &mut Selected,
+) -> Result<Response<BoxBody<Bytes, BoxError>>, hyper::Error> {
+    let started = tokio::time::Instant::now();
+
+    if req.uri().path() == state.args.health_path
+        && request_host(&req)
+            .ok()
+            .is_none_or(|host| extract_domain(&state.domain_regex, host).is_none())
+    {
+        return Ok(Response::new(full("ok")));
+    }
+
+    if state.maintenance.load(Ordering::Relaxed) {
+        return Ok(maintenance_response(&state.args));
+    }
+
+    if client.queue_full {
+        let mut resp = error_response(StatusCode::SERVICE_UNAVAILABLE, "server is busy\n");
+        resp.headers_mut()
+            .insert("retry-after", HeaderValue::from_static("1"));
+        // HTTP/2 does not allow connection headers
+        if req.version() < Version::HTTP_2 {
+            resp.headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+        return Ok(resp);
+    }
+
+    if state.args.redirect_https && !client.tls {
+        return Ok(https_redirect(&req));
+    }
+
+    // preflights never carry credentials, so they come before the auth check
+    if state.cors.is_some() && Cors::is_preflight(req.method(), req.headers()) {
+        let mut resp = Response::new(full(""));
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+        Cors::preflight(req.headers(), resp.headers_mut());
+        return Ok(resp);
+    }
+
+    if let Some(auth) = &state.basic_auth {
+        if !auth.check(req.headers()) {
+            let mut resp = error_response(StatusCode::UNAUTHORIZED, "unauthorized\n");
+            resp.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"http-proxy\""),
+            );
+            return Ok(resp);
+        }
+        req.headers_mut().remove(AUTHORIZATION);
+    }
+
+    if let (Some(limiter), Some(ip)) = (&state.rate_limiter, client.ip()) {
+        if let Err(retry_after) = limiter.check(ip) {
+            let mut resp = error_response(StatusCode::TOO_MANY_REQUESTS, "too many requests\n");
+            resp.headers_mut().insert(
+                "retry-after",
+                HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+            );
+            return Ok(resp);
+        }
+    }
+
+    if state.args.enable_connect && req.method() == Method::CONNECT {
+        return Ok(connect_tunnel(req, &state, selected).await);
+    }
+
+    let host = match request_host(&req) {
+        Ok(host) => host,
+        Err(message) => return Ok(error_response(StatusCode::BAD_REQUEST, message)),
+    };
+    let original_host = HeaderValue::from_str(host).expect("host was a header value");
+    let original_port = host
+        .rsplit_once(':')
+        .map(|(_, port)| port)
+        .filter(|port| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+        .map(str::to_owned);
+    let (host, ip) = match extract_subdomain(&state.domain_regex, host) {
+        Some((host, ip)) if !host.is_empty() => (Some(host), ip),
+        Some((_, ip)) => (
+            state
+                .args
+                .default_subdomain
+                .as_deref()
+                .map(|default| match default {
+                    "" => String::new(),
+                    default => format!("{}.", default),
+                }),
+            ip,
+        ),
+        None => (None, None),
+    };
+    let Some(host) = host else {
+        return Ok(error_response(
+            StatusCode::MISDIRECTED_REQUEST,
+            "host must be of the form <sub>.<ip>.nip.io\n",
+        ));
+    };
+    if let Some(allowed) = &state.allowed_subdomains {
+        if !allowed.is_match(host.trim_end_matches('.')) {
+            return Ok(error_response(
+                StatusCode::FORBIDDEN,
+                "subdomain is not allowed\n",
+            ));
+        }
+    }
+    let route = state.routes.get(host.trim_end_matches('.'));
+    // content routes come before the catch-all route
+    let content_backend = match route {
+        Some(_) => None,
+        None => content_route(req.headers(), &state.content_routes),
+    };
+    let route = route.or_else(|| state.routes.get("*").filter(|_| content_backend.is_none()));
+    let limits = Limits {
+        timeout_ms: state.args.request_timeout_ms,
+        max_body_bytes: state.args.max_body_bytes,
+        methods: None,
+    }
+    .or(route.map_or_else(Limits::default, |(_, limits)| limits.clone()));
+    if let Some(methods) = &limits.methods {
+        if !methods.contains(req.method()) {
+            let mut resp = error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n");
+            let allow = methods
+                .iter()
+                .map(Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+            resp.headers_mut().insert(
+                ALLOW,
+                HeaderValue::from_str(&allow).expect("methods are tokens"),
+            );
+            return Ok(resp);
+        }
+    }
+    let deadline = limits
+        .timeout_ms
+        .map(|ms| started + Duration::from_millis(ms));
+    // a deadline the client sent can only make the request's shorter
+    let incoming = state
+        .args
+        .propagate_deadline
+        .then(|| incoming_deadline(req.headers(), Instant::now(), SystemTime::now()))
+        .flatten();
+    if incoming.is_some_and(|incoming| incoming <= Instant::now()) {
+        return Ok(gateway_error(
+            StatusCode::GATEWAY_TIMEOUT,
+            "request deadline has passed\n",
+            "deadline passed",
+        ));
+    }
+    let deadline = match (deadline, incoming.map(tokio::time::Instant::from_std)) {
+        (Some(deadline), Some(incoming)) => Some(deadline.min(incoming)),
+        (deadline, incoming) => deadline.or(incoming),
+    };
+    let mut set_cookie = None;
+    let canary = state
+        .canaries
+        .get(host.trim_end_matches('.'))
+        .map(|&(addr, percent)| (addr, fastrand::f64() * 100.0 < percent));
+    selected.canary = canary.map(|(_, picked)| picked);
+    let canary = canary.filter(|&(_, picked)| picked).map(|(addr, _)| addr);
+    let backend = match (canary, route, content_backend) {
+        (Some(addr), _, _) => Backend::Addr(addr),
+        #[cfg(unix)]
+        (None, Some((Upstream::Unix(path), _)), _) => Backend::Unix(path.clone()),
+        (None, Some((Upstream::Balancer(balancer), _)), _) => {
+            let usable = |addr: SocketAddr| {
+                health::is_up(&state.health, addr)
+                    && state
+                        .breaker
+                        .as_ref()
+                        .is_none_or(|breaker| !breaker.is_open(&addr.to_string()))
+            };
+            let share = |addr: SocketAddr| match state.args.slow_start_ms {
+                _ if !usable(addr) => 0.0,
+                Some(window) => {
+                    health::slow_start_share(&state.health, addr, Duration::from_millis(window))
+                }
+                None => 1.0,
+            };
+            let sticky = state.args.sticky_cookie.as_deref().map(|name| {
+                let index = cookie(req.headers(), name).and_then(|value| value.parse().ok());
+                (name, index)
+            });
+            let addr = match sticky {
+                Some((_, Some(index))) => balancer.get(index).filter(|&addr| usable(addr)),
+                _ => None,
+            }
+            .unwrap_or_else(|| balancer.pick(share));
+            if let Some((name, index)) = sticky {
+                let chosen = balancer.index_of(addr);
+                if chosen != index {
+                    let chosen = chosen.expect("picked from the balancer");
+                    set_cookie = Some(
+                        HeaderValue::from_str(&format!("{}={}; Path=/; HttpOnly", name, chosen))
+                            .expect("cookie name is a header value"),
+                    );
+                }
+            }
+            Backend::Addr(addr)
+        }
+        (None, None, Some(backend)) => backend.clone(),
+        (None, None, None) if state.args.backend_from_host => match ip {
+            Some(ip) if state.args.deny_private && is_private(ip) => {
+                return Ok(error_response(
+                    StatusCode::FORBIDDEN,
+                    "backend address is not allowed\n",
+                ))
+            }
+            Some(ip) => Backend::Addr(SocketAddr::new(ip, state.args.backend_port)),
+            None => {
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "host does not contain a valid address\n",
+                ))
+            }
+        },
+        (None, None, None) => {
+            Backend::Host(state.args.backend_host.clone(), state.args.backend_port)
+        }
+    };
+    selected.backend = Some(backend.clone());
+    let subdomain = host.trim_end_matches('.').to_owned();
+    let (kept, suffix) = map_suffix(&host, &state.suffix_map, &state.args.domain_suffix);
+    let host = match &state.host_rewrite {
+        HostRewrite::Suffix => match suffixed_host(
+            kept,
+            suffix,
+            original_port
+                .as_deref()
+                .filter(|_| state.args.preserve_port),
+        ) {
+            Ok(host) => host,
+            Err(host) => {
+                warn!("rewritten host {:?} is not a valid header value", host);
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid host header\n",
+                ));
+            }
+        },
+        HostRewrite::Preserve => original_host,
+        HostRewrite::Fixed(host) => host.clone(),
+    };
+
+    info!("connecting to {:?}", host);
+    info!("headers: {:?}", req.headers());
+
+    if let Some(original) = req.headers_mut().remove("host") {
+        req.headers_mut().insert("x-forwarded-host", original);
+    }
+    req.headers_mut().insert("host", host);
+    set_forwarded_headers(
+        req.headers_mut(),
+        &client,
+        state.args.trust_forwarded,
+        &state.trusted_proxies,
+    );
+    if let Some(name) = &state.subdomain_header {
+        // extract_domain only lets letters, digits, dashes and dots through
+        req.headers_mut()
+            .insert(name, HeaderValue::from_str(&subdomain).unwrap());
+    }
+    if state.args.propagate_deadline {
+        match deadline {
+            Some(deadline) => {
+                let millis =
+                    deadline_millis(deadline.into_std(), Instant::now(), SystemTime::now());
+                req.headers_mut()
+                    .insert(DEADLINE_HEADER, HeaderValue::from(millis));
+            }
+            None => {
+                req.headers_mut().remove(DEADLINE_HEADER);
+            }
+        }
+    }
+    set_headers(req.headers_mut(), &state.request_headers);
+
+    // the backend is always spoken to over HTTP/1.1
+    if req.version() == Version::HTTP_2 {
+        *req.version_mut() = Version::HTTP_11;
+        if let Some(path_and_query) = req.uri().path_and_query().cloned() {
+            *req.uri_mut() = Uri::from(path_and_query);
+        }
+    }
+
+    if let Some(prefix) = &state.args.strip_prefix {
+        if let Some(uri) = strip_path_prefix(req.uri(), prefix) {
+            *req.uri_mut() = uri;
+        }
+    }
+
+    let method = req.method().clone();
+    let compress = if state.args.compress && method != Method::HEAD {
+        pick_encoding(req.headers(), &state.args.compress_algos)
+    } else {
+        None
+    };
+    let request_upgrade_type = get_upgrade_type(req.headers());
+    let requested_subprotocols = request_subprotocols(req.headers());
+    let request_upgraded = req.extensions_mut().remove::<OnUpgrade>();
+    strip_hop_by_hop(req.headers_mut(), request_upgrade_type.is_some());
+
+    // GETs are cached by what the backend is asked for
+    let cache = state
+        .cache
+        .as_ref()
+        .filter(|_| method == Method::GET && request_upgrade_type.is_none())
+        .map(|cache| {
+            let key = format!(
+                "{} {} {}",
+                backend,
+                req.headers()
+                    .get("host")
+                    .and_then(|host| host.to_str().ok())
+                    .unwrap_or_default(),
+                req.uri()
+                    .path_and_query()
+                    .map_or("/", |path_and_query| path_and_query.as_str())
+            );
+            (cache, key, req.headers().clone())
+        });
+    if let Some((cache, key, headers)) = &cache {
+        if !cache::skip_lookup(headers) {
+            if let Some(resp) = cache.get(key, headers) {
+                debug!("serving {} from the cache", key);
+                return Ok(finish_response(
+                    resp.map(full),
+                    compress,
+                    &state.args,
+                    set_cookie,
+                ));
+            }
+        }
+    }
+    let cache = cache.filter(|(_, _, headers)| !cache::no_store(headers));
+
+    let body_limit = match (&request_upgrade_type, limits.max_body_bytes) {
+        (None, Some(limit)) => limit,
+        _ => usize::MAX,
+    };
+    if req.body().size_hint().lower() > body_limit as u64 {
+        return Ok(error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "request body too large\n",
+        ));
+    }
+    // the body stays a stream, each frame going on to the backend as it
+    // arrives, so uploads of any size take no more memory than a few frames;
+    // only `--log-bodies` buffers, and only bodies of a known small size
+    //
+    // for `Expect: 100-continue` hyper sends the client its own 100 Continue
+    // once the body is first polled, which is when it goes to the backend;
+    // requests turned away before then never have the client send the body,
+    // and the backend's interim response is consumed by the client connection
+    let req = req.map(|body| Limited::new(body, body_limit).boxed());
+    let req = if state.args.log_bodies && request_upgrade_type.is_none() {
+        let (parts, body) = req.into_parts();
+        match log_body("request", body).await {
+            Ok(body) => Request::from_parts(parts, body),
+            Err(err) if err.is::<LengthLimitError>() => {
+                return Ok(error_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "request body too large\n",
+                ))
+            }
+            Err(err) => {
+                debug!("failed to read request body: {:?}", err);
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "failed to read request body\n",
+                ));
+            }
+        }
+    } else {
+        req
+    };
+
+    // upgraded connections are taken over by the tunnel, so they never come
+    // from or go back to the pool
+    let key = backend.to_string();
+    // upgraded connections and those announcing a particular client with
+    // the PROXY protocol are not shared either
+    let poolable = request_upgrade_type.is_none() && !state.args.send_proxy_protocol;
+    let wait = Duration::from_millis(state.args.pool_wait_timeout_ms);
+    let mut slot = match state.pool.acquire(&key, wait).await {
+        Ok(slot) => slot,
+        Err(_) => {
+            warn!("no connection to backend {} freed up in time", backend);
+            let mut resp = error_response(StatusCode::SERVICE_UNAVAILABLE, "backend is busy\n");
+            resp.headers_mut()
+                .insert("retry-after", HeaderValue::from_static("1"));
+            return Ok(resp);
+        }
+    };
+    let pooled = if poolable {
+        state.pool.checkout(&key)
+    } else {
+        None
+    };
+    // tunnels may legitimately stay quiet for any amount of time
+    let io_timeout = state
+        .args
+        .backend_io_timeout_ms
+        .filter(|_| request_upgrade_type.is_none())
+        .map(Duration::from_millis);
+    // requests without side effects may be retried when the backend cannot
+    // be reached; nothing has been sent at that point
+    let retries = if method.is_safe() {
+        state.args.max_retries
+    } else {
+        0
+    };
+    let mut sender = match (pooled, &backend) {
+        (Some(sender), _) => {
+            debug!("reusing pooled connection to {}", backend);
+            sender
+        }
+        // `State::new` turns down the PROXY protocol and TLS for local sockets
+        #[cfg(unix)]
+        (None, Backend::Unix(path)) => {
+            let connect = || connect_unix(&state, &backend, path);
+            let stream = match with_retries(&backend, retries, deadline, connect).await {
+                Ok(stream) => stream,
+                Err(err) => return Ok(err.response()),
+            };
+            match handshake(TimeoutIo::new(stream, io_timeout), state.args.header_case).await {
+                Ok(sender) => sender,
+                Err(err) => return Ok(handshake_failed(&backend, err)),
+            }
+        }
+        (None, _) => {
+            let connect = || connect_backend(&state, &backend);
+            let mut stream = match with_retries(&backend, retries, deadline, connect).await {
+                Ok(stream) => stream,
+                Err(err) => return Ok(err.response()),
+            };
+
+            if state.args.send_proxy_protocol {
+                let header = match stream.peer_addr() {
+                    Ok(backend_addr) => proxy_protocol::encode_v2(client.addr, backend_addr),
+                    Err(err) => return Ok(backend_io_error(&backend, err)),
+                };
+                if let Err(err) = stream.write_all(&header).await {
+                    return Ok(backend_io_error(&backend, err));
+                }
+            }
+
+            let handshake_result = match &state.backend_tls {
+                Some(connector) => match connect_tls(&state, connector, &backend, stream).await {
+                    Ok(stream) => {
+                        handshake(TimeoutIo::new(stream, io_timeout), state.args.header_case).await
+                    }
+                    Err(resp) => return Ok(resp),
+                },
+                None => handshake(TimeoutIo::new(stream, io_timeout), state.args.header_case).await,
+            };
+            match handshake_result {
+                Ok(sender) => sender,
+                Err(err) => return Ok(handshake_failed(&backend, err)),
+            }
+        }
+    };
+
+    let sent = sender.send_request(req);
+    let sent = match deadline {
+        Some(deadline) => match timeout_at(deadline, sent).await {
+            Ok(sent) => sent,
+            Err(_) => {
+                error!("backend {} did not respond in time", backend);
+                return Ok(gateway_error(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "backend did not respond in time\n",
+                    "request timed out",
+                ));
+            }
+        },
+        None => sent.await,
+    };
+    let mut resp = match sent {
+        Ok(resp) => resp,
+        Err(err) if is_body_too_large(&err) => {
+            return Ok(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request body too large\n",
+            ))
+        }
+        Err(err) if is_io_timeout(&err) => {
+            error!("backend {} stalled: {:?}", backend, err);
+            return Ok(gateway_error(
+                StatusCode::GATEWAY_TIMEOUT,
+                "backend did not respond in time\n",
+                "backend connection timed out",
+            ));
+        }
+        Err(err) => {
+            error!("failed to send request to backend {}: {:?}", backend, err);
+            return Ok(gateway_error(
+                StatusCode::BAD_GATEWAY,
+                "failed to send request to backend\n",
+                format!("request failed: {}", err),
+            ));
+        }
+    };
+    let status = resp.status();
+    // a connection that switched protocols is no use for further requests
+    if poolable && status != StatusCode::SWITCHING_PROTOCOLS {
+        state.pool.release(key, sender);
+    }
+    strip_hop_by_hop(
+        resp.headers_mut(),
+        status == StatusCode::SWITCHING_PROTOCOLS,
+    );
+
+    if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+        // a 101 the client did not ask for leaves it with a connection it
+        // cannot use
+        let response_upgrade_type = get_upgrade_type(resp.headers());
+        if request_upgrade_type != response_upgrade_type {
+            error!(
+                "backend tried to switch to protocol {:?} when {:?} was requested",
+                response_upgrade_type, request_upgrade_type
+            );
+            return Ok(gateway_error(
+                StatusCode::BAD_GATEWAY,
+                "backend switched to an unrequested protocol\n",
+                "unrequested upgrade",
+            ));
+        }
+        let Some(request_upgraded) = request_upgraded else {
+            error!("request does not have an upgrade extension");
+            return Ok(upgrade_failed());
+        };
+        let response_upgraded = match response_upgrade(&mut resp, &backend) {
+            Some(upgrade) => match upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    error!(
+                        "failed to upgrade backend {} connection: {:?}",
+                        backend, err
+                    );
+                    return Ok(upgrade_failed());
+                }
+            },
+            None => return Ok(upgrade_failed()),
+        };
+
+        debug!("Responding to a connection upgrade response");
+
+        // clients are expected to fail the handshake themselves, the
+        // warning tells operators why
+        if let Some(chosen) = unrequested_subprotocol(&requested_subprotocols, resp.headers()) {
+            warn!(
+                "backend {} chose websocket subprotocol {:?}, the client requested {:?}",
+                backend, chosen, requested_subprotocols
+            );
+        }
+
+        // the tunnel keeps the backend connection busy
+        let tunnel_slot = slot.take();
+        tokio::spawn(async move {
+            let _slot = tunnel_slot;
+            let request_upgraded = match request_upgraded.await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    error!("failed to upgrade client connection: {:?}", err);
+                    return;
+                }
+            };
+
+            tunnel(
+                tokio_io::TokioIo::new(response_upgraded),
+                tokio_io::TokioIo::new(request_upgraded),
+            )
+            .await;
+        });
+    }
+
+    // upgraded connections are not bound by the request timeout
+    let mut resp = match deadline {
+        Some(deadline) if resp.status() != StatusCode::SWITCHING_PROTOCOLS => {
+            resp.map(|b| Deadline::new(b, deadline).boxed())
+        }
+        _ => resp.map(|b| b.map_err(BoxError::from).boxed()),
+    };
+    // the slot is given back once the client has the whole body
+    if let Some(slot) = slot {
+        resp = resp.map(|b| {
+            b.map_frame(move |frame| {
+                let _ = &slot;
+                frame
+            })
+            .boxed()
+        });
+    }
+    if state.args.log_bodies && resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        let (parts, body) = resp.into_parts();
+        match log_body("response", body).await {
+            Ok(body) => resp = Response::from_parts(parts, body),
+            Err(err) => {
+                error!("failed to read response body from {}: {:?}", backend, err);
+                return Ok(gateway_error(
+                    StatusCode::BAD_GATEWAY,
+                    "failed to read response body\n",
+                    "failed to read response body",
+                ));
+            }
+        }
+    }
+
+    // the cache keeps responses as the backend sent them, compression and
+    // cookies are per client
+    if let Some((cache, key, headers)) = cache {
+        let max_age = cache::max_age(resp.status(), resp.headers());
+        let fits = resp
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|len| len <= cache.capacity() as u64);
+        if let Some(max_age) = max_age.filter(|_| fits) {
+            let (parts, body) = resp.into_parts();
+            let stored = match body.collect().await {
+                Ok(body) => Response::from_parts(parts, body.to_bytes()),
+                Err(err) => {
+                    error!("failed to read response body from {}: {:?}", backend, err);
+                    return Ok(gateway_error(
+                        StatusCode::BAD_GATEWAY,
+                        "failed to read response body\n",
+                        "failed to read response body",
+                    ));
+                }
+            };
+            cache.insert(key, &headers, &stored, max_age);
+            resp = stored.map(full);
+        }
+    }
+
+    Ok(finish_response(resp, compress, &state.args, set_cookie))
+}
+
+/// compress the response with the coding picked for `--compress` and add
+/// the sticky cookie, both for responses from the backend and from the cache
+fn finish_response(
+    mut resp: Response<BoxBody<Bytes, BoxError>>,
+    compress: Option<CompressAlgo>,
+    args: &Args,
+    set_cookie: Option<HeaderValue>,
+) -> Response<BoxBody<Bytes, BoxError>> {
+    let too_small = args
+        .compress_min_bytes
+        .is_some_and(|min| resp.body().size_hint().exact().is_some_and(|len| len < min));
+    // ranges point into the body as the backend sent it
+    let compress = compress.filter(|_| {
+        !too_small
+            && !matches!(
+                resp.status(),
+                StatusCode::SWITCHING_PROTOCOLS
+                    | StatusCode::NO_CONTENT
+                    | StatusCode::NOT_MODIFIED
+                    | StatusCode::PARTIAL_CONTENT
+            )
+            && !resp.headers().contains_key(CONTENT_ENCODING)
+            && !resp.headers().contains_key(CONTENT_RANGE)
+            && !cache::no_transform(resp.headers())
+    });
+    if let Some(algo) = compress {
+        let headers = resp.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+        // the compressed body is no longer byte for byte what the tag stood for
+        if let Some(etag) = headers
+            .get(ETAG)
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            let weak = HeaderValue::from_bytes(&weak).expect("a header value with a prefix");
+            headers.insert(ETAG, weak);
+        }
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(algo.coding()));
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+        resp = resp.map(|b| match algo {
+            CompressAlgo::Gzip => Compress::gzip(b, args.compress_level).boxed(),
+            CompressAlgo::Br => Compress::brotli(b, args.compress_level).boxed(),
+        });
+    }
+    if let Some(cookie) = set_cookie {
+        resp.headers_mut().append(SET_COOKIE, cookie);
+    }
+    resp
+}
+
+/// `<subdomain>.<domain-suffix>[:<port>]` for the backend, or the string that
+/// could not be made into a header value
+fn suffixed_host(subdomain: &str, suffix: &str, port: Option<&str>) -> Result<HeaderValue, String> {
+    let host = match port {
+        Some(port) => format!("{}{}:{}", subdomain, suffix, port),
+        None => format!("{}{}", subdomain, suffix),
+    };
+    HeaderValue::from_str(&host).map_err(|_| host)
+}
+
+/// value of the cookie `name` sent by the client
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+/// the one of `offered` the client gives the highest `q` in
+/// `accept-encoding`, directly or through `*`, the first one on a tie; none
+/// if it refuses them all with `q=0`
+fn pick_encoding(headers: &HeaderMap, offered: &[CompressAlgo]) -> Option<CompressAlgo> {
+    let codings = headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().filter(|name| !name.is_empty())?;
+            let q = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((name.to_ascii_lowercase(), q))
+        })
+        .collect::<Vec<_>>();
+    let q = |name: &str| {
+        codings
+            .iter()
+            .find(|(coding, _)| coding == name)
+            .map(|(_, q)| *q)
+    };
+    let mut best = None;
+    for &algo in offered {
+        let q = q(algo.coding()).or_else(|| q("*")).unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((algo, q));
+        }
+    }
+    best.map(|(algo, _)| algo)
+}
+
+fn backend_io_error(backend: &Backend, err: std::io::Error) -> Response<BoxBody<Bytes, BoxError>> {
+    error!("failed to write to backend {}: {:?}", backend, err);
+    gateway_error(
+        StatusCode::BAD_GATEWAY,
+        "failed to connect to backend\n",
+        format!("write failed: {}", err.kind()),
+    )
+}
+
+/// answer `CONNECT` with 200 once the requested address is reached, then
+/// tunnel the client connection to it
+async fn connect_tunnel(
+    mut req: Request<hyper::body::Incoming>,
+    state: &State,
+    selected: &mut Selected,
+) -> Response<BoxBody<Bytes, BoxError>> {
+    let Some((host, port)) = req
+        .uri()
+        .authority()
+        .and_then(|authority| Some((authority.host().to_owned(), authority.port_u16()?)))
+    else {
+        return error_response(StatusCode::BAD_REQUEST, "CONNECT needs a host:port\n");
+    };
+    // bracketed IPv6 literals only make sense on the request line
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let Some(request_upgraded) = req.extensions_mut().remove::<OnUpgrade>() else {
+        error!("CONNECT request does not have an upgrade extension");
+        return error_response(StatusCode::BAD_REQUEST, "CONNECT is not supported here\n");
+    };
+
+    let backend = Backend::Host(host, port);
+    selected.backend = Some(backend.clone());
+    let stream = match connect_backend(state, &backend).await {
+        Ok(stream) => stream,
+        Err(err) => return err.response(),
+    };
+
+    tokio::spawn(async move {
+        match request_upgraded.await {
+            Ok(upgraded) => tunnel(tokio_io::TokioIo::new(upgraded), stream).await,
+            Err(err) => warn!("failed to upgrade CONNECT to {}: {:?}", backend, err),
+        }
+    });
+    Response::new(full(""))
+}
+
+/// copy between the two sides of an upgraded connection until both are done;
+/// either of them going away abruptly is part of normal operation
+async fn tunnel<A, B>(mut a: A, mut b: B)
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    match copy_bidirectional(&mut a, &mut b).await {
+        Ok((a_to_b, b_to_a)) => debug!(
+            "upgraded connection closed after {} and {} bytes",
+            a_to_b, b_to_a
+        ),
+        Err(err) if is_disconnect_kind(err.kind()) => {
+            debug!("upgraded connection closed: {:?}", err)
+        }
+        Err(err) => warn!("copying between upgraded connections failed: {:?}", err),
+    }
+}
+
+/// log `body` for `--log-bodies` if it is small enough, handing back an
+/// equivalent one; bodies of unknown length are never buffered
+async fn log_body(
+    what: &str,
+    body: BoxBody<Bytes, BoxError>,
+) -> Result<BoxBody<Bytes, BoxError>, BoxError> {
+    match body.size_hint().upper() {
+        Some(0) => return Ok(body),
+        Some(len) if len <= LOG_BODY_LIMIT => {}
+        _ => {
+            debug!(
+                "{} body not logged, its length is unknown or above {} bytes",
+                what, LOG_BODY_LIMIT
+            );
+            return Ok(body);
+        }
+    }
+    let bytes = body.collect().await?.to_bytes();
+    match std::str::from_utf8(&bytes) {
+        Ok(text) => debug!(body = text, "{} body", what),
+        Err(_) => {
+            let hex = bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            debug!(body_hex = hex, "{} body", what)
+        }
+    }
+    Ok(full(bytes))
+}
+
+/// the pending upgrade of a 101 from the backend; hyper attaches one to every
+/// such response, but a missing one is logged and answered with 502 rather
+/// than trusted
+fn response_upgrade<B>(resp: &mut Response<B>, backend: &Backend) -> Option<OnUpgrade> {
+    let upgrade = resp.extensions_mut().remove::<OnUpgrade>();
+    if upgrade.is_none() {
+        error!(
+            "backend {} switched protocols without an upgradable connection",
+            backend
+        );
+    }
+    upgrade
+}
+
+fn upgrade_failed() -> Response<BoxBody<Bytes, BoxError>> {
+    gateway_error(
+        StatusCode::BAD_GATEWAY,
+        "failed to upgrade backend connection\n",
+        "upgrade failed",
+    )
+}
+
+/// re-read the configuration, switching requests over to it once it turns out
+/// valid; there is no signal to trigger this on Windows
+#[cfg_attr(windows, allow(dead_code))]
+fn reload(
+    matches: &clap::ArgMatches,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let args = config::load(matches)?;
+    let reloaded = state.current().reload(args)?;
+    state.replace(reloaded);
+    Ok(())
+}
+
+/// TLS handshake for `--backend-tls`, bounded by the connect timeout
+async fn connect_tls(
+    state: &State,
+    connector: &TlsConnector,
+    backend: &Backend,
+    stream: TcpStream,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>, Response<BoxBody<Bytes, BoxError>>> {
+    let server_name = backend.server_name().map_err(|err| {
+        error!("invalid TLS server name for backend {}: {:?}", backend, err);
+        gateway_error(
+            StatusCode::BAD_GATEWAY,
+            "failed to connect to backend\n",
+            "invalid TLS server name",
+        )
+    })?;
+    match timeout(
+        Duration::from_millis(state.args.connect_timeout_ms),
+        connector.connect(server_name, stream),
+    )
+    .await
+    {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(err)) => {
+            error!("TLS handshake with backend {} failed: {:?}", backend, err);
+            Err(gateway_error(
+                StatusCode::BAD_GATEWAY,
+                "failed to connect to backend\n",
+                format!("TLS handshake failed: {}", err.kind()),
+            ))
+        }
+        Err(_) => {
+            error!("timed out in the TLS handshake with backend {}", backend);
+            Err(gateway_error(
+                StatusCode::GATEWAY_TIMEOUT,
+                "timed out connecting to backend\n",
+                "TLS handshake timed out",
+            ))
+        }
+    }
+}
+
+/// start an HTTP/1.1 connection to the backend over `io`, driven by a task of
+/// its own
+async fn handshake<S>(
+    io: S,
+    header_case: HeaderCase,
+) -> Result<SendRequest<BoxBody<Bytes, BoxError>>, hyper::Error>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (sender, conn) = Builder::new()
+        .preserve_header_case(header_case.preserve())
+        .title_case_headers(header_case.title())
+        .handshake(tokio_io::TokioIo::new(io))
+        .await?;
+    tokio::task::spawn(async move {
+        match conn.with_upgrades().await {
+            Err(err) if is_disconnect(&err) => {
+                debug!("backend connection closed: {:?}", err)
+            }
+            Err(err) if is_io_timeout(&err) => {
+                warn!("backend connection timed out: {:?}", err)
+            }
+            Err(err) => error!("backend connection failed: {:?}", err),
+            Ok(()) => {}
+        }
+    });
+    Ok(sender)
+}
+
+#[derive(Debug)]
+enum ConnectError {
+    /// with the reason for `--expose-error-detail`
+    Failed(String),
+    TimedOut,
+    /// the circuit breaker did not let the attempt through
+    CircuitOpen,
+    /// `--deny-private` and the backend resolved to a private address
+    Denied,
+    /// the request deadline passed while connecting or between retries
+    DeadlineExceeded,
+}
+
+impl ConnectError {
+    fn response(&self) -> Response<BoxBody<Bytes, BoxError>> {
+        match self {
+            ConnectError::Failed(detail) => gateway_error(
+                StatusCode::BAD_GATEWAY,
+                "failed to connect to backend\n",
+                detail.clone(),
+            ),
+            ConnectError::TimedOut => gateway_error(
+                StatusCode::GATEWAY_TIMEOUT,
+                "timed out connecting to backend\n",
+                "connect timed out",
+            ),
+            ConnectError::CircuitOpen => {
+                error_response(StatusCode::SERVICE_UNAVAILABLE, "backend is unavailable\n")
+            }
+            ConnectError::Denied => {
+                error_response(StatusCode::FORBIDDEN, "backend address is not allowed\n")
+            }
+            ConnectError::DeadlineExceeded => gateway_error(
+                StatusCode::GATEWAY_TIMEOUT,
+                "backend did not respond in time\n",
+                "request timed out",
+            ),
+        }
+    }
+}
+
+/// the header `--propagate-deadline` reads and sets
+const DEADLINE_HEADER: &str = "x-request-deadline";
+
+/// the deadline a client sent in milliseconds since the Unix epoch, as an
+/// instant on the clock `now` is from; `wall` is the time at `now`
+fn incoming_deadline(headers: &HeaderMap, now: Instant, wall: SystemTime) -> Option<Instant> {
+    let millis = headers
+        .get(DEADLINE_HEADER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    let deadline = UNIX_EPOCH + Duration::from_millis(millis);
+    Some(match deadline.duration_since(wall) {
+        Ok(remaining) => now + remaining,
+        Err(passed) => now.checked_sub(passed.duration()).unwrap_or(now),
+    })
+}
+
+/// `deadline` in milliseconds since the Unix epoch
+fn deadline_millis(deadline: Instant, now: Instant, wall: SystemTime) -> u64 {
+    let deadline = wall + deadline.saturating_duration_since(now);
+    deadline
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+fn handshake_failed(backend: &Backend, err: hyper::Error) -> Response<BoxBody<Bytes, BoxError>> {
+    error!("HTTP handshake with backend {} failed: {:?}", backend, err);
+    gateway_error(
+        StatusCode::BAD_GATEWAY,
+        "failed to connect to backend\n",
+        format!("handshake failed: {}", err),
+    )
+}
+
+/// the addresses `backend` resolves to, none of them private if `deny_private`
+async fn resolve(backend: &Backend, deny_private: bool) -> Result<Vec<SocketAddr>, ConnectError> {
+    let addrs = match backend {
+        Backend::Addr(addr) => vec![*addr],
+        Backend::Host(host, port) => match lookup_host((host.as_str(), *port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(err) => {
+                error!("failed to resolve backend {}: {:?}", backend, err);
+                return Err(ConnectError::Failed("DNS lookup failed".to_string()));
+            }
+        },
+        #[cfg(unix)]
+        Backend::Unix(_) => return Err(ConnectError::Failed("not a TCP backend".to_string())),
+    };
+    // every candidate is checked, as the connect may try any of them
+    if deny_private {
+        if let Some(addr) = addrs.iter().find(|addr| is_private(addr.ip())) {
+            warn!(
+                "backend {} resolved to private address {}",
+                backend,
+                addr.ip()
+            );
+            return Err(ConnectError::Denied);
+        }
+    }
+    Ok(addrs)
+}
+
+async fn connect_backend(state: &State, backend: &Backend) -> Result<TcpStream, ConnectError> {
+    connect_with(state, backend, async {
+        // the checked addresses are the ones connected to, so a second
+        // lookup cannot answer differently
+        let addrs = resolve(backend, state.args.deny_private).await?;
+        let stream = TcpStream::connect(&addrs[..]).await.map_err(|err| {
+            error!("failed to connect to backend {}: {:?}", backend, err);
+            ConnectError::Failed(format!("connect failed: {}", err.kind()))
+        })?;
+        if let Err(err) = state.socket_options.apply(&stream) {
+            warn!(
+                "failed to set options on backend {} socket: {:?}",
+                backend, err
+            );
+        }
+        Ok(stream)
+    })
+    .await
+}
+
+#[cfg(unix)]
+async fn connect_unix(
+    state: &State,
+    backend: &Backend,
+    path: &Path,
+) -> Result<tokio::net::UnixStream, ConnectError> {
+    connect_with(state, backend, async {
+        tokio::net::UnixStream::connect(path).await.map_err(|err| {
+            error!("failed to connect to backend {}: {:?}", backend, err);
+            ConnectError::Failed(format!("connect failed: {}", err.kind()))
+        })
+    })
+    .await
+}
+
+/// `connect` again up to `retries` times while the backend cannot be reached,
+/// backing off a little longer each time, and giving up once `deadline` passes
+async fn with_retries<S, F, Fut>(
+    backend: &Backend,
+    retries: u32,
+    deadline: Option<tokio::time::Instant>,
+    connect: F,
+) -> Result<S, ConnectError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<S, ConnectError>>,
+{
+    let attempts = async {
+        let mut attempt = 0;
+        loop {
+            match connect().await {
+                Err(ConnectError::Failed(_) | ConnectError::TimedOut) if attempt < retries => {
+                    attempt += 1;
+                    let backoff = RETRY_BACKOFF * attempt;
+                    // no use waiting for a retry the deadline cuts short
+                    if deadline
+                        .is_some_and(|deadline| tokio::time::Instant::now() + backoff >= deadline)
+                    {
+                        return Err(ConnectError::DeadlineExceeded);
+                    }
+                    warn!(
+                        "retrying connect to backend {} ({}/{})",
+                        backend, attempt, retries
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                result => return result,
+            }
+        }
+    };
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return attempts.await,
+    };
+    timeout_at(deadline, attempts).await.unwrap_or_else(|_| {
+        error!("backend {} did not respond in time", backend);
+        Err(ConnectError::DeadlineExceeded)
+    })
+}
+
+/// `connect` to `backend` within the connect timeout, unless its circuit is
+/// open, recording the outcome with the breaker and in the metrics
+async fn connect_with<S>(
+    state: &State,
+    backend: &Backend,
+    connect: impl Future<Output = Result<S, ConnectError>>,
+) -> Result<S, ConnectError> {
+    let key = backend.to_string();
+    if let Some(breaker) = &state.breaker {
+        if !breaker.allow(&key) {
+            debug!("circuit for backend {} is open", backend);
+            return Err(ConnectError::CircuitOpen);
+        }
+    }
+
+    let result = timeout(
+        Duration::from_millis(state.args.connect_timeout_ms),
+        connect,
+    )
+    .await;
+    if let Some(breaker) = &state.breaker {
+        match result {
+            Ok(Ok(_)) => breaker.success(&key),
+            Ok(Err(ConnectError::Denied)) => {}
+            _ => breaker.failure(&key),
+        }
+    }
+    match result {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(ConnectError::Denied)) => Err(ConnectError::Denied),
+        Ok(Err(err)) => {
+            state.metrics.connect_failed();
+            Err(err)
+        }
+        Err(_) => {
+            error!(
+                "timed out connecting to backend {} after {}ms",
+                backend, state.args.connect_timeout_ms
+            );
+            state.metrics.connect_failed();
+            Err(ConnectError::TimedOut)
+        }
+    }
+}
+
+/// whether sending failed because the request body exceeded `--max-body-bytes`
+fn is_body_too_large(err: &hyper::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if err.is::<LengthLimitError>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// whether a backend read or write ran into `--backend-io-timeout-ms`
+fn is_io_timeout(err: &hyper::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            return err.kind() == std::io::ErrorKind::TimedOut;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// `uri` without the leading `prefix` path segments, or `None` if its path
+/// does not start with them
+fn strip_path_prefix(uri: &Uri, prefix: &str) -> Option<Uri> {
+    let rest = uri.path().strip_prefix(prefix.trim_end_matches('/'))?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let path = if rest.is_empty() { "/" } else { rest };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// append the client address to `x-forwarded-for`, dropping what the client
+/// sent there unless it is in `trusted_proxies`, and our element to
+/// `forwarded`, dropping what the client sent there unless `trust_forwarded`,
+/// and record the scheme the client used in `x-forwarded-proto` and the TLS
+/// server name in `x-forwarded-sni`
+fn set_forwarded_headers(
+    headers: &mut HeaderMap,
+    client: &Client,
+    trust_forwarded: bool,
+    trusted_proxies: &[IpNet],
+) {
+    let trusted = client.addr.is_some_and(|addr| {
+        let ip = addr.ip().to_canonical();
+        trusted_proxies.iter().any(|network| network.contains(&ip))
+    });
+    if !trusted {
+        headers.remove("x-forwarded-for");
+    }
+    let mut forwarded_for = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Some(addr) = client.addr {
+        if !forwarded_for.is_empty() {
+            forwarded_for.push_str(", ");
+        }
+        forwarded_for.push_str(&addr.ip().to_string());
+    }
+
+    if !forwarded_for.is_empty() {
+        headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+    }
+
+    let mut elements = String::new();
+    if trust_forwarded {
+        for value in headers.get_all(FORWARDED) {
+            if let Ok(value) = value.to_str() {
+                elements.push_str(value);
+                elements.push_str(", ");
+            }
+        }
+    }
+    elements.push_str(&forwarded::element(
+        client.addr.map(|addr| addr.ip()),
+        client.tls,
+    ));
+    headers.insert(FORWARDED, elements.parse().unwrap());
+    headers.insert(
+        "x-forwarded-proto",
+        HeaderValue::from_static(if client.tls { "https" } else { "http" }),
+    );
+    // never pass on a value the client made up itself
+    match client.sni.as_deref().map(HeaderValue::from_str) {
+        Some(Ok(sni)) => headers.insert("x-forwarded-sni", sni),
+        _ => headers.remove("x-forwarded-sni"),
+    };
+}
+
+/// headers that only apply to a single connection (RFC 7230 section 6.1)
+///
+/// `trailer` is left out: it announces the trailer fields of the message
+/// itself, which are passed on like any other part of the body
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// remove hop-by-hop headers, including the ones named in `connection`;
+/// for an upgrade handshake `connection: upgrade` and `upgrade` are kept, and
+/// `te: trailers` is kept so the next hop still sends trailers, which gRPC
+/// depends on
+fn strip_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let accepts_trailers = headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"));
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        if !(upgrade && name == UPGRADE) {
+            headers.remove(name);
+        }
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        if !(upgrade && name == UPGRADE) {
+            headers.remove(name);
+        }
+    }
+    if upgrade {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+    if accepts_trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+/// the subprotocols a websocket client offers in `sec-websocket-protocol`
+fn request_subprotocols(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|protocol| protocol.trim().to_string())
+        .filter(|protocol| !protocol.is_empty())
+        .collect()
+}
+
+/// the subprotocol the backend settled on if the client did not offer it
+fn unrequested_subprotocol(requested: &[String], response: &HeaderMap) -> Option<String> {
+    let chosen = response.get(SEC_WEBSOCKET_PROTOCOL)?;
+    let chosen = String::from_utf8_lossy(chosen.as_bytes())
+        .trim()
+        .to_string();
+    (!requested.contains(&chosen)).then_some(chosen)
+}
+
+/// header values that are not valid ASCII are treated as no upgrade
+fn get_upgrade_type(headers: &HeaderMap) -> Option<String> {
+    #[allow(clippy::blocks_in_conditions)]
+    if headers
+        .get("connection")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .any(|e| e.trim().eq_ignore_ascii_case("upgrade"))
+        })
+        .unwrap_or(false)
+    {
+        if let Some(upgrade_value) = headers.get("upgrade").and_then(|value| value.to_str().ok()) {
+            debug!("Found upgrade header with value: {}", upgrade_value);
+
+            return Some(upgrade_value.to_owned());
+        }
+    }
+
+    None
+}
+
+/// connections take the settings below the HTTP layer from the state current
+/// when they are accepted, requests take theirs when they are received
+async fn serve<L: Listener>(listener: L, shared: SharedState, mut shutdown: watch::Receiver<bool>) {
+    let mut connections = JoinSet::new();
+    let max_connections = shared.current().args.max_connections;
+    let queue_depth = shared.current().args.queue_depth;
+    // with a queue, a fixed pool of workers takes connections off it rather
+    // than accept waiting for a permit
+    let queue = match (max_connections, queue_depth) {
+        (Some(workers), Some(depth)) => {
+            let (queue, queued) = mpsc::channel(depth);
+            let queued = Arc::new(tokio::sync::Mutex::new(queued));
+            for _ in 0..workers {
+                let queued = queued.clone();
+                let shared = shared.clone();
+                let shutdown = shutdown.clone();
+                connections.spawn(async move {
+                    loop {
+                        let next = queued.lock().await.recv().await;
+                        let Some((stream, peer)) = next else {
+                            break;
+                        };
+                        handle_connection(stream, peer, shared.clone(), shutdown.clone(), false)
+                            .await;
+                    }
+                });
+            }
+            Some(queue)
+        }
+        _ => None,
+    };
+    let limit = max_connections
+        .filter(|_| queue.is_none())
+        .map(|max| Arc::new(Semaphore::new(max)));
+    loop {
+        let permit = match &limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => Some(permit.expect("semaphore is never closed")),
+                _ = shutdown_requested(&mut shutdown) => break,
+            },
+            None => None,
+        };
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = shutdown_requested(&mut shutdown) => break,
+        };
+        let (mut stream, mut peer) = match accepted {
+            Ok(sock) => sock,
+            Err(e) if listener::is_transient(&e) => {
+                warn!("Error when accepting, retrying: {:?}", e);
+                tokio::select! {
+                    _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                    _ = shutdown_requested(&mut shutdown) => break,
+                }
+            }
+            Err(e) => {
+                error!("Error when accepting {:?}", e);
+                break;
+            }
+        };
+        if let Err(err) = L::set_options(&stream, &shared.current().socket_options) {
+            warn!("failed to set options on socket of {:?}: {:?}", peer, err);
+        }
+        let queue_full = match &queue {
+            Some(queue) => match queue.try_send((stream, peer)) {
+                Ok(()) => continue,
+                Err(err) => {
+                    debug!("connection queue is full, answering {:?} with 503", peer);
+                    (stream, peer) = err.into_inner();
+                    true
+                }
+            },
+            None => false,
+        };
+
+        let shared = shared.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            handle_connection(stream, peer, shared, shutdown, queue_full).await
+        });
+    }
+    drop(listener);
+    // the workers finish what is queued, then find the queue closed
+    drop(queue);
+
+    if connections.is_empty() {
+        return;
+    }
+    info!("waiting for {} connections to finish", connections.len());
+    let grace = Duration::from_millis(shared.current().args.shutdown_grace_ms);
+    let drained = timeout(grace, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "dropping {} connections still open after the shutdown grace period",
+            connections.len()
+        );
+    }
+}
+
+/// the PROXY protocol header and TLS handshake of a new connection, then its
+/// requests; with `queue_full` every one of them is answered with 503
+async fn handle_connection<S>(
+    mut stream: S,
+    mut peer: Option<SocketAddr>,
+    shared: SharedState,
+    shutdown: watch::Receiver<bool>,
+    queue_full: bool,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let state = shared.current();
+    let _open = state.metrics.connection_opened();
+    let handshake_timeout = Duration::from_millis(state.args.handshake_timeout_ms);
+    if state.args.accept_proxy_protocol {
+        match timeout(handshake_timeout, proxy_protocol::read_header(&mut stream)).await {
+            Ok(Ok(Some(addr))) => peer = Some(addr),
+            Ok(Ok(None)) => {}
+            Ok(Err(err)) => {
+                error!("rejecting connection from {:?}: {}", peer, err);
+                return;
+            }
+            Err(_) => {
+                debug!("no PROXY protocol header from {:?} in time", peer);
+                return;
+            }
+        }
+    }
+    match state.tls.clone() {
+        Some(acceptor) => match timeout(handshake_timeout, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => {
+                let client = Client {
+                    addr: peer,
+                    tls: true,
+                    sni: stream.get_ref().1.server_name().map(str::to_owned),
+                    forwarded: None,
+                    queue_full,
+                };
+                let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                serve_connection(stream, state, shared, client, h2, shutdown).await
+            }
+            Ok(Err(err)) => error!("TLS handshake with {:?} failed: {:?}", peer, err),
+            Err(_) => debug!("TLS handshake with {:?} timed out", peer),
+        },
+        None => {
+            let client = Client {
+                addr: peer,
+                tls: false,
+                sni: None,
+                forwarded: None,
+                queue_full,
+            };
+            let h2 = state.args.http2;
+            serve_connection(stream, state, shared, client, h2, shutdown).await
+        }
+    }
+}
+
+/// resolves once shutdown was requested or its sender is gone
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|&stop| stop).await;
+}
+
+async fn serve_connection<S>(
+    stream: S,
+    state: Arc<State>,
+    shared: SharedState,
+    client: Client,
+    h2: bool,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let idle_timeout = state.args.idle_timeout_ms.map(Duration::from_millis);
+    let max_header_bytes = state.args.max_header_bytes;
+    let header_case = state.args.header_case;
+    let activity = idle::Activity::new();
+    let io = tokio_io::TokioIo::new(activity.track(stream));
+    let service = service_fn(move |req| proxy(req, shared.current(), client.clone()));
+
+    // on shutdown or once idle, in-flight requests are finished, then the
+    // connection closes
+    let stop = async {
+        tokio::select! {
+            _ = shutdown_requested(&mut shutdown) => {}
+            _ = activity.idle(idle_timeout) => debug!("closing idle connection"),
+        }
+    };
+    tokio::pin!(stop);
+    let result = if h2 {
+        let mut builder = http2::Builder::new(tokio_io::TokioExecutor);
+        if let Some(max) = max_header_bytes {
+            builder.max_header_list_size(max);
+        }
+        let conn = builder.serve_connection(io, service);
+        tokio::pin!(conn);
+        tokio::select! {
+            result = conn.as_mut() => result,
+            _ = &mut stop => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        }
+    } else {
+        let mut builder = http1::Builder::new();
+        builder
+            .preserve_header_case(header_case.preserve())
+            .title_case_headers(header_case.title());
+        if let Some(max) = max_header_bytes {
+            builder.max_buf_size(max as usize);
+        }
+        if let Some(idle_timeout) = idle_timeout {
+            builder
+                .timer(tokio_io::TokioTimer)
+                .header_read_timeout(idle_timeout);
+        }
+        let conn = builder.serve_connection(io, service).with_upgrades();
+        tokio::pin!(conn);
+        tokio::select! {
+            result = conn.as_mut() => result,
+            _ = &mut stop => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        }
+    };
+    match result {
+        Err(err) if is_disconnect(&err) => debug!("client connection closed: {:?}", err),
+        Err(err) => error!("failed to serve connection: {:?}", err),
+        Ok(()) => {}
+    }
+}
+
+/// whether `err` is the peer going away mid-exchange rather than a fault
+/// worth reporting
+fn is_disconnect(err: &hyper::Error) -> bool {
+    if err.is_incomplete_message() || err.is_canceled() || err.is_closed() {
+        return true;
+    }
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            return is_disconnect_kind(err.kind());
+        }
+        source = err.source();
+    }
+    false
+}
+
+fn is_disconnect_kind(kind: std::io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// listen for SIGINT and SIGTERM, unless `--no-default-shutdown-signals`,
+/// and the `--shutdown-signal`s, each with the name it is logged by
+#[cfg(unix)]
+fn shutdown_signals(args: &Args) -> std::io::Result<Vec<(String, Signal)>> {
+    let mut kinds = Vec::new();
+    if !args.no_default_shutdown_signals {
+        kinds.push(("SIGINT".to_string(), SignalKind::interrupt()));
+        kinds.push(("SIGTERM".to_string(), SignalKind::terminate()));
+    }
+    for name in &args.shutdown_signals {
+        let kind = parse_signal(name).expect("validated by the parser");
+        kinds.push((name.clone(), kind));
+    }
+    kinds
+        .into_iter()
+        .map(|(name, kind)| Ok((name, signal(kind)?)))
+        .collect()
+}
+
+#[cfg(unix)]
+async fn shutdown_signal(signals: Vec<(String, Signal)>) {
+    let mut received = JoinSet::new();
+    for (name, mut signal) in signals {
+        received.spawn(async move {
+            signal.recv().await;
+            name
+        });
+    }
+    if let Some(Ok(name)) = received.join_next().await {
+        debug!("{} received", name);
+    }
+}
+
+/// flip maintenance mode on every SIGUSR1
+#[cfg(unix)]
+async fn toggle_maintenance(state: SharedState) {
+    let mut sig_usr1 = signal(SignalKind::user_defined1()).unwrap();
+    while sig_usr1.recv().await.is_some() {
+        let enabled = !state
+            .current()
+            .maintenance
+            .fetch_xor(true, Ordering::Relaxed);
+        info!(
+            "SIGUSR1 received, maintenance mode {}",
+            if enabled { "on" } else { "off" }
+        );
+    }
+}
+
+/// reload the configuration on every SIGHUP
+#[cfg(unix)]
+async fn reload_on_sighup(matches: clap::ArgMatches, state: SharedState) {
+    let mut sig_hup = signal(SignalKind::hangup()).unwrap();
+    while sig_hup.recv().await.is_some() {
+        match reload(&matches, &state) {
+            Ok(()) => info!("SIGHUP received, configuration reloaded"),
+            Err(err) => error!(
+                "SIGHUP received, keeping the current configuration: {}",
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn shutdown_signal() {
+    let mut ctrl_close = ctrl_close().unwrap();
+    tokio::select! {
+        _ = ctrl_c() => debug!("'Ctrl C' received"),
+        _ = ctrl_close.recv() => debug!("'Ctrl Close' received"),
+    }
+}
+
+/// bind to an IPv4/IPv6 literal (optionally in brackets) or a hostname,
+/// using the first resolved address that can be bound
+async fn bind(host: &str, port: u16, backlog: u32) -> std::io::Result<TcpListener> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut last_err = None;
+    for addr in lookup_host((host, port)).await? {
+        match listen(addr, backlog) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{} did not resolve to any address", host),
+        )
+    }))
+}
+
+/// a listening socket at `addr` with room for `backlog` pending connections
+fn listen(addr: SocketAddr, backlog: u32) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // like TcpListener::bind, so restarts need not wait for TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// everything startup would fail on, short of opening sockets
+async fn check_config(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    State::new(args.clone())?;
+    let host = args
+        .proxy_host
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    if args.proxy_ports.is_empty() {
+        return Err("no --proxy-port to listen on".into());
+    }
+    if lookup_host((host, args.proxy_ports[0]))
+        .await?
+        .next()
+        .is_none()
+    {
+        return Err(format!("{} did not resolve to any address", host).into());
+    }
+    Ok(())
+}
+
+async fn bind_tcp(args: &Args) -> std::io::Result<Vec<TcpListener>> {
+    if args.proxy_ports.is_empty() {
+        eprintln!("no --proxy-port to listen on");
+        std::process::exit(1);
+    }
+    let mut listeners = Vec::new();
+    for &port in &args.proxy_ports {
+        let listener = match bind(&args.proxy_host, port, args.listen_backlog).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                eprintln!("failed to listen on {}:{}: {}", args.proxy_host, port, err);
+                std::process::exit(1);
+            }
+        };
+        info!(
+            "Listening on {}://{}",
+            if args.tls_cert.is_some() {
+                "https"
+            } else {
+                "http"
+            },
+            listener.local_addr()?
+        );
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+fn log_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = Args::command().get_matches();
+    let args = match config::load(&matches) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let tracer_provider = match &args.otlp_endpoint {
+        Some(endpoint) => match telemetry::provider(endpoint) {
+            Ok(provider) => Some(provider),
+            Err(err) => {
+                eprintln!("invalid --otlp-endpoint {:?}: {}", endpoint, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // `RUST_LOG` is about the log output, request spans are exported
+    // whatever it says
+    tracing_subscriber::registry()
+        .with(
+            log_layer(args.log_format, std::io::stdout).with_filter(EnvFilter::from_default_env()),
+        )
+        .with(
+            tracer_provider
+                .as_ref()
+                .map(|provider| telemetry::layer(provider).with_filter(LevelFilter::INFO)),
+        )
+        .init();
+    if args.check_config {
+        match check_config(&args).await {
+            Ok(()) => {
+                println!("configuration ok");
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!("invalid configuration: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let state = State::new(args.clone())?;
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let metrics_server = match args.metrics_port {
+        Some(port) => {
+            let listener = match bind(&args.proxy_host, port, args.listen_backlog).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    eprintln!("failed to listen on {}:{}: {}", args.proxy_host, port, err);
+                    std::process::exit(1);
+                }
+            };
+            info!(
+                "Serving metrics on http://{}/metrics",
+                listener.local_addr()?
+            );
+            Some(tokio::spawn(metrics::serve(
+                listener,
+                state.metrics.clone(),
+                shutdown_rx.clone(),
+            )))
+        }
+        None => None,
+    };
+
+    if let Some(interval) = args.health_check_interval_ms {
+        let mut backends = args
+            .routes
+            .iter()
+            .flat_map(|route| route.targets.iter().map(|target| target.addr))
+            .collect::<Vec<_>>();
+        backends.sort();
+        backends.dedup();
+        tokio::spawn(health::run(
+            backends,
+            args.health_check_path.clone(),
+            Duration::from_millis(interval),
+            Duration::from_millis(args.connect_timeout_ms),
+            state.backend_tls.clone(),
+            state.health.clone(),
+            shutdown_rx.clone(),
+        ));
+    }
+
+    let state = SharedState::new(state);
+    #[cfg(unix)]
+    {
+        tokio::spawn(toggle_maintenance(state.clone()));
+        tokio::spawn(reload_on_sighup(matches.clone(), state.clone()));
+    }
+    #[cfg(unix)]
+    let shutdown = match shutdown_signals(&args) {
+        Ok(signals) => shutdown_signal(signals),
+        Err(err) => {
+            eprintln!("failed to listen for shutdown signals: {}", err);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(windows)]
+    let shutdown = shutdown_signal();
+    let admin_server = match args.admin_port {
+        Some(port) => {
+            let listener = match bind(&args.admin_host, port, args.listen_backlog).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    eprintln!("failed to listen on {}:{}: {}", args.admin_host, port, err);
+                    std::process::exit(1);
+                }
+            };
+            info!(
+                "Serving the admin API on http://{}/admin/",
+                listener.local_addr()?
+            );
+            Some(tokio::spawn(admin::serve(
+                listener,
+                state.clone(),
+                shutdown_rx.clone(),
+            )))
+        }
+        None => None,
+    };
+    let mut servers = JoinSet::new();
+    #[cfg(unix)]
+    if let Some(path) = &args.proxy_unix_socket {
+        if ["proxy_host", "proxy_ports"]
+            .iter()
+            .any(|id| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine))
+        {
+            warn!("--proxy-unix-socket is set, ignoring --proxy-host/--proxy-port");
+        }
+        let listener = match listener::bind_unix(path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                eprintln!("failed to listen on {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        };
+        info!("Listening on unix:{}", path.display());
+        servers.spawn(serve(listener, state.clone(), shutdown_rx.clone()));
+    }
+    #[cfg(unix)]
+    if args.systemd_socket_activation {
+        let listeners = match listener::systemd_listeners() {
+            Ok(listeners) => listeners,
+            Err(err) => {
+                eprintln!("failed to adopt the sockets passed by systemd: {}", err);
+                std::process::exit(1);
+            }
+        };
+        for listener in listeners {
+            match listener.local_addr() {
+                Ok(addr) => info!("Listening on {} passed by systemd", addr),
+                Err(_) => info!("Listening on a socket passed by systemd"),
+            }
+            servers.spawn(serve(listener, state.clone(), shutdown_rx.clone()));
+        }
+    }
+    if servers.is_empty() {
+        for listener in bind_tcp(&args).await? {
+            servers.spawn(serve(listener, state.clone(), shutdown_rx.clone()));
+        }
+    }
+
+    // a listener failing to accept takes the others down with it
+    tokio::select! {
+        _ = servers.join_next() => {},
+        _ = shutdown => {},
+    }
+
+    info!("shutting down");
+    let _ = shutdown_tx.send(true);
+    while let Some(result) = servers.join_next().await {
+        result?;
+    }
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.await?;
+    }
+    if let Some(admin_server) = admin_server {
+        admin_server.await?;
+    }
+    // flush the spans still waiting for a batch
+    if let Some(provider) = tracer_provider {
+        if let Err(err) = provider.shutdown() {
+            warn!("failed to export the remaining spans: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::AsyncReadExt as _;
+
+    async fn spawn_proxy(args: &[&str]) -> SocketAddr {
+        let args = Args::parse_from(std::iter::once("http-proxy").chain(args.iter().copied()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = SharedState::new(State::new(args).unwrap());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(async move {
+            serve(listener, state, shutdown_rx).await;
+            drop(shutdown_tx);
+        });
+        addr
+    }
+
+    /// backend answering every request with an `x-backend: <name>` header and
+    /// the received request headers as the body
+    async fn spawn_service<F, Fut>(service: F) -> SocketAddr
+    where
+        F: Fn(Request<hyper::body::Incoming>) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = Response<BoxBody<Bytes, BoxError>>> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service.clone();
+                let service = service_fn(move |req| {
+                    let resp = service(req);
+                    async move { Ok::<_, BoxError>(resp.await) }
+                });
+                tokio::spawn(
+                    http1::Builder::new()
+                        .serve_connection(tokio_io::TokioIo::new(stream), service)
+                        .with_upgrades(),
+                );
+            }
+        });
+        addr
+    }
+
+    async fn spawn_backend(name: &'static str) -> SocketAddr {
+        spawn_counting_backend(name).await.0
+    }
+
+    /// like `spawn_backend`, also counting the accepted connections
+    async fn spawn_counting_backend(name: &'static str) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let service = service_fn(move |req: Request<hyper::body::Incoming>| async move {
+                    let (parts, body) = req.into_parts();
+                    body.collect().await?;
+                    let headers: String = parts
+                        .headers
+                        .iter()
+                        .map(|(k, v)| format!("{}: {}\n", k, v.to_str().unwrap()))
+                        .collect();
+                    Ok::<_, BoxError>(
+                        Response::builder()
+                            .header("x-backend", name)
+                            .body(full(headers))
+                            .unwrap(),
+                    )
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(tokio_io::TokioIo::new(stream), service),
+                );
+            }
+        });
+        (addr, connections)
+    }
+
+    async fn send_raw(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
     fn test_regex() {
-        assert!(extract_domain("foo.192.168.1.1.nip.io") == Some("foo.".to_string()));
-        assert!(extract_domain("foo.bar.192.168.1.1.nip.io") == Some("foo.bar.".to_string()));
-        assert!(extract_domain("foo.192.168.1.1.nip.io:8888") == Some("foo.".to_string()));
-        assert!(extract_domain("foo.bar.192.168.1.1.nip.io:8888") == Some("foo.bar.".to_string()));
+        let xp = domain_regex(&["nip.io".to_string()], &['.']);
+        assert!(extract_domain(&xp, "foo.192.168.1.1.nip.io") == Some("foo.".to_string()));
+        assert!(extract_domain(&xp, "foo.bar.192.168.1.1.nip.io") == Some("foo.bar.".to_string()));
+        assert!(extract_domain(&xp, "foo.192.168.1.1.nip.io:8888") == Some("foo.".to_string()));
+        assert!(
+            extract_domain(&xp, "foo.bar.192.168.1.1.nip.io:8888") == Some("foo.bar.".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_host() {
+        let addr = spawn_proxy(&[]).await;
+        let response = send_raw(addr, "GET / HTTP/1.0\r\n\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.0 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_misdirected_host() {
+        let addr = spawn_proxy(&[]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backend_down() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port().to_string();
+        drop(closed);
+
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        // a listener that never accepts and has a backlog of one; once the
+        // backlog is full further SYNs are dropped and connect() hangs
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let blackhole = socket.listen(0).unwrap();
+        let blackhole_addr = blackhole.local_addr().unwrap();
+        let mut pending = Vec::new();
+        for _ in 0..4 {
+            if let Ok(Ok(s)) = timeout(
+                Duration::from_millis(100),
+                TcpStream::connect(blackhole_addr),
+            )
+            .await
+            {
+                pending.push(s);
+            }
+        }
+
+        let port = blackhole_addr.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--connect-timeout-ms",
+            "200",
+        ])
+        .await;
+        let started = std::time::Instant::now();
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "{}",
+            response
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_retries_within_deadline() {
+        // the same never accepting listener as in test_connect_timeout
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let blackhole = socket.listen(0).unwrap();
+        let blackhole_addr = blackhole.local_addr().unwrap();
+        let mut pending = Vec::new();
+        for _ in 0..4 {
+            if let Ok(Ok(s)) = timeout(
+                Duration::from_millis(100),
+                TcpStream::connect(blackhole_addr),
+            )
+            .await
+            {
+                pending.push(s);
+            }
+        }
+
+        // five retries would take over two seconds without the deadline
+        let port = blackhole_addr.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--connect-timeout-ms",
+            "300",
+            "--max-retries",
+            "5",
+            "--request-timeout-ms",
+            "500",
+        ])
+        .await;
+        let started = std::time::Instant::now();
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("backend did not respond in time\n"),
+            "{}",
+            response
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let api = spawn_backend("api").await;
+        let default = spawn_backend("default").await;
+        let route = format!("api={}", api);
+        let port = default.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--route",
+            &route,
+        ])
+        .await;
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: api.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.contains("x-backend: api\r\n"), "{}", response);
+        assert!(response.contains("host: api.localhost\n"), "{}", response);
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: web.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.contains("x-backend: default\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(
+            parse_route("api=127.0.0.1:3000"),
+            Ok(Route {
+                subdomain: "api".to_string(),
+                targets: vec![Target {
+                    addr: "127.0.0.1:3000".parse().unwrap(),
+                    weight: 1
+                }],
+                socket: None,
+                limits: Limits::default(),
+            })
+        );
+        assert_eq!(
+            parse_route("api=127.0.0.1:3000;timeout=2000;max-body=1048576")
+                .map(|route| route.limits),
+            Ok(Limits {
+                timeout_ms: Some(2000),
+                max_body_bytes: Some(1048576),
+                methods: None,
+            })
+        );
+        assert_eq!(
+            parse_route("web=127.0.0.1:80,[::1]:81@3").map(|route| route.targets),
+            Ok(vec![
+                Target {
+                    addr: "127.0.0.1:80".parse().unwrap(),
+                    weight: 1
+                },
+                Target {
+                    addr: "[::1]:81".parse().unwrap(),
+                    weight: 3
+                },
+            ])
+        );
+        assert!(parse_route("api").is_err());
+        assert!(parse_route("api=127.0.0.1").is_err());
+        assert!(parse_route("api=127.0.0.1:80@0").is_err());
+        assert!(parse_route("api=127.0.0.1:80,").is_err());
+        assert!(parse_route("api=127.0.0.1:80;timeout=soon").is_err());
+        assert!(parse_route("api=127.0.0.1:80;retries=3").is_err());
+        assert!(parse_route("app=unix:").is_err());
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let client = Client {
+            addr: Some("10.0.0.1:50000".parse().unwrap()),
+            tls: false,
+            sni: None,
+            forwarded: None,
+            queue_full: false,
+        };
+        let mut headers = HeaderMap::new();
+        set_forwarded_headers(&mut headers, &client, false, &[]);
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+        assert_eq!(headers["forwarded"], "for=10.0.0.1;proto=http");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+
+        headers.insert("forwarded", "for=1.2.3.4".parse().unwrap());
+        set_forwarded_headers(&mut headers, &client, true, &[]);
+        assert_eq!(headers["forwarded"], "for=1.2.3.4, for=10.0.0.1;proto=http");
+        headers.insert("forwarded", "for=1.2.3.4".parse().unwrap());
+        set_forwarded_headers(&mut headers, &client, false, &[]);
+        assert_eq!(headers["forwarded"], "for=10.0.0.1;proto=http");
+
+        headers.insert("x-forwarded-for", "1.2.3.4".parse().unwrap());
+        headers.append("x-forwarded-for", "5.6.7.8".parse().unwrap());
+        set_forwarded_headers(&mut headers, &client, false, &[]);
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        headers.insert("x-forwarded-for", "1.2.3.4".parse().unwrap());
+        headers.append("x-forwarded-for", "5.6.7.8".parse().unwrap());
+        set_forwarded_headers(&mut headers, &client, false, &trusted);
+        assert_eq!(headers["x-forwarded-for"], "1.2.3.4, 5.6.7.8, 10.0.0.1");
+        assert_eq!(headers.get_all("x-forwarded-for").iter().count(), 1);
+        headers.insert("x-forwarded-for", "1.2.3.4".parse().unwrap());
+        let elsewhere = ["192.168.0.0/16".parse().unwrap()];
+        set_forwarded_headers(&mut headers, &client, false, &elsewhere);
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+
+        headers.insert("x-forwarded-sni", "spoofed".parse().unwrap());
+        set_forwarded_headers(&mut headers, &client, false, &[]);
+        assert!(!headers.contains_key("x-forwarded-sni"));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_for_reaches_backend() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.contains("x-forwarded-for: 127.0.0.1\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("x-forwarded-proto: http\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxies() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let request = "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nX-Forwarded-For: 1.2.3.4\r\nConnection: close\r\n\r\n";
+
+        let trusted =
+            spawn_proxy(&["--backend-port", &port, "--trusted-proxies", "127.0.0.0/8"]).await;
+        let response = send_raw(trusted, request).await;
+        assert!(
+            response.contains("x-forwarded-for: 1.2.3.4, 127.0.0.1\n"),
+            "{}",
+            response
+        );
+
+        let untrusted =
+            spawn_proxy(&["--backend-port", &port, "--trusted-proxies", "10.0.0.1"]).await;
+        let response = send_raw(untrusted, request).await;
+        assert!(
+            response.contains("x-forwarded-for: 127.0.0.1\n"),
+            "{}",
+            response
+        );
+
+        assert!(Args::try_parse_from(["http-proxy", "--trusted-proxies", "10.0.0.0/33"]).is_err());
+        assert!(Args::try_parse_from(["http-proxy", "--trusted-proxies", "::1/128"]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+
+        let host = "foo.127.0.0.1.nip.io";
+        let cert = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("http-proxy-test-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ])
+        .await;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(host).unwrap(), stream)
+            .await
+            .unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    host
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("x-forwarded-proto: https\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains(&format!("x-forwarded-sni: {}\n", host)),
+            "{}",
+            response
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_host() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.contains("x-forwarded-host: foo.192.168.1.1.nip.io\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("host: foo.localhost\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port().to_string();
+        drop(closed);
+
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+        let response = send_raw(
+            addr,
+            "GET /healthz HTTP/1.1\r\nHost: 10.0.0.5:8100\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+
+        // nip.io hosts are still forwarded, here to the closed port
+        let response = send_raw(
+            addr,
+            "GET /healthz HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http2() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--http2",
+        ])
+        .await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http2::handshake(
+            tokio_io::TokioExecutor,
+            tokio_io::TokioIo::new(stream),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(conn);
+
+        let req = Request::builder()
+            .uri("http://foo.192.168.1.1.nip.io/path?q=1")
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.version(), Version::HTTP_2);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("host: foo.localhost\n"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_pool_reuses_connections() {
+        let (backend, connections) = spawn_counting_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(tokio_io::TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        for _ in 0..3 {
+            let req = Request::builder()
+                .header("host", "foo.192.168.1.1.nip.io")
+                .body(http_body_util::Empty::<Bytes>::new())
+                .unwrap();
+            let resp = sender.send_request(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            resp.into_body().collect().await.unwrap();
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let slow = spawn_service(|_req| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Response::new(full("slow"))
+        })
+        .await;
+        let port = slow.port().to_string();
+
+        let args = Args::parse_from([
+            "http-proxy",
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve(
+            listener,
+            SharedState::new(State::new(args).unwrap()),
+            shutdown_rx,
+        ));
+
+        let request = tokio::spawn(send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\n\r\n",
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown_tx.send(true).unwrap();
+
+        let response = request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("slow"), "{}", response);
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_wildcard_suffixes() {
+        let xp = domain_regex(&["nip.io".to_string(), "sslip.io".to_string()], &['.']);
+        assert_eq!(
+            extract_domain(&xp, "foo.192.168.1.1.sslip.io"),
+            Some("foo.".to_string())
+        );
+        assert_eq!(
+            extract_domain(&xp, "foo.192.168.1.1.nip.io"),
+            Some("foo.".to_string())
+        );
+
+        let xp = domain_regex(&["dev.example.com".to_string()], &['.']);
+        assert_eq!(
+            extract_domain(&xp, "foo.bar.10.0.0.1.dev.example.com:8080"),
+            Some("foo.bar.".to_string())
+        );
+        assert_eq!(extract_domain(&xp, "foo.192.168.1.1.nip.io"), None);
+        // dots in the suffix are literal
+        assert_eq!(extract_domain(&xp, "foo.10.0.0.1.devXexample.com"), None);
+    }
+
+    #[test]
+    fn test_map_suffix() {
+        let map = ["app=internal", "admin.app=admin.internal"]
+            .into_iter()
+            .map(|entry| parse_suffix_map(entry).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            map_suffix("foo.app.", &map, "localhost"),
+            ("foo.", "internal")
+        );
+        assert_eq!(
+            map_suffix("foo.admin.app.", &map, "localhost"),
+            ("foo.", "admin.internal")
+        );
+        assert_eq!(map_suffix("app.", &map, "localhost"), ("", "internal"));
+        // only whole labels match
+        assert_eq!(
+            map_suffix("foo.webapp.", &map, "localhost"),
+            ("foo.webapp.", "localhost")
+        );
+        assert_eq!(map_suffix("foo.", &map, "localhost"), ("foo.", "localhost"));
+
+        assert!(parse_suffix_map("app").is_err());
+        assert!(parse_suffix_map("=internal").is_err());
+        assert!(parse_suffix_map("app=").is_err());
+        assert!(parse_suffix_map("a_p=internal").is_err());
+    }
+
+    #[test]
+    fn test_ip_separators() {
+        let xp = domain_regex(&["sslip.io".to_string()], &['.', '-']);
+        assert_eq!(
+            extract_subdomain(&xp, "foo.bar.192-168-1-1.sslip.io:8080"),
+            Some(("foo.bar.".to_string(), Some([192, 168, 1, 1].into())))
+        );
+        assert_eq!(
+            extract_domain(&xp, "foo.192.168.1.1.sslip.io"),
+            Some("foo.".to_string())
+        );
+        // one separator throughout the address, and only the allowed ones
+        assert_eq!(extract_domain(&xp, "foo.192-168.1-1.sslip.io"), None);
+        assert_eq!(extract_domain(&xp, "foo.192_168_1_1.sslip.io"), None);
+        // dashed IPv6 addresses are still told apart
+        assert_eq!(
+            extract_subdomain(&xp, "foo.2001-db8--1.sslip.io"),
+            Some(("foo.".to_string(), Some("2001:db8::1".parse().unwrap())))
+        );
+
+        let xp = domain_regex(&["nip.io".to_string()], &['_']);
+        assert_eq!(
+            extract_subdomain(&xp, "foo.10_0_0_1.nip.io"),
+            Some(("foo.".to_string(), Some([10, 0, 0, 1].into())))
+        );
+        assert_eq!(extract_domain(&xp, "foo.10.0.0.1.nip.io"), None);
+
+        assert!(Args::try_parse_from(["http-proxy", "--ip-separator", ":"]).is_err());
+    }
+
+    #[test]
+    fn test_host_normalization() {
+        let xp = domain_regex(&["nip.io".to_string()], &['.']);
+        assert_eq!(
+            extract_domain(&xp, "FOO.192.168.1.1.NIP.IO"),
+            Some("foo.".to_string())
+        );
+        assert_eq!(
+            extract_domain(&xp, "foo.192.168.1.1.nip.io."),
+            Some("foo.".to_string())
+        );
+        assert_eq!(
+            extract_domain(&xp, "Foo.Bar.192.168.1.1.nip.io.:8080"),
+            Some("foo.bar.".to_string())
+        );
+        assert_eq!(extract_domain(&xp, "foo.192.168.1.1.nip.io.."), None);
+
+        let xp = domain_regex(&["SSLIP.io".to_string()], &['.']);
+        assert_eq!(
+            extract_domain(&xp, "foo.2001-DB8--1.sslip.io"),
+            Some("foo.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_embedded_address() {
+        let xp = domain_regex(&["nip.io".to_string(), "sslip.io".to_string()], &['.']);
+        let ip = |host| extract_subdomain(&xp, host).unwrap().1;
+        assert_eq!(ip("foo.192.168.1.1.nip.io"), Some([192, 168, 1, 1].into()));
+        assert_eq!(ip("10.0.0.5.nip.io:8080"), Some([10, 0, 0, 5].into()));
+        assert_eq!(ip("foo.1.2.3.999.nip.io"), None);
+        assert_eq!(
+            ip("foo-2001-db8--1.sslip.io"),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(ip("foo.--1.sslip.io"), Some(Ipv6Addr::LOCALHOST.into()));
+
+        assert!(is_private([127, 0, 0, 1].into()));
+        assert!(is_private([10, 1, 2, 3].into()));
+        assert!(is_private([169, 254, 0, 1].into()));
+        assert!(is_private("fd00::1".parse().unwrap()));
+        assert!(is_private("::ffff:192.168.0.1".parse().unwrap()));
+        assert!(!is_private([93, 184, 216, 34].into()));
+        assert!(!is_private("2001:db8::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_backend_from_host() {
+        let backend = spawn_backend("embedded").await;
+        let port = backend.port().to_string();
+        let request = |host: &str| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                host
+            )
+        };
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "backend.invalid",
+            "--backend-port",
+            &port,
+            "--backend-from-host",
+        ])
+        .await;
+        let response = send_raw(addr, &request("foo.127.0.0.1.nip.io")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("x-backend: embedded\r\n"), "{}", response);
+        let response = send_raw(addr, &request("foo.1.2.3.999.nip.io")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--backend-from-host",
+            "--deny-private",
+        ])
+        .await;
+        let response = send_raw(addr, &request("foo.127.0.0.1.nip.io")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deny_private() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port, "--deny-private"]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{}",
+            response
+        );
+
+        let public = Backend::Addr("93.184.216.34:80".parse().unwrap());
+        assert!(resolve(&public, true).await.is_ok());
+        let loopback = Backend::Host("localhost".to_string(), 80);
+        assert!(matches!(
+            resolve(&loopback, true).await,
+            Err(ConnectError::Denied)
+        ));
+        assert!(resolve(&loopback, false).await.is_ok());
+        let metadata = Backend::Addr("169.254.169.254:80".parse().unwrap());
+        assert!(matches!(
+            resolve(&metadata, true).await,
+            Err(ConnectError::Denied)
+        ));
+    }
+
+    #[test]
+    fn test_ipv6_hosts() {
+        let xp = domain_regex(&["sslip.io".to_string()], &['.']);
+        assert_eq!(
+            extract_domain(&xp, "foo.2001-db8--1.sslip.io"),
+            Some("foo.".to_string())
+        );
+        assert_eq!(
+            extract_domain(&xp, "foo.bar.--1.sslip.io:8080"),
+            Some("foo.bar.".to_string())
+        );
+        assert_eq!(
+            extract_domain(&xp, "foo-2001-db8--1.sslip.io"),
+            Some("foo.".to_string())
+        );
+        assert_eq!(
+            extract_domain(&xp, "my-app-fe80--1.sslip.io"),
+            Some("my-app.".to_string())
+        );
+        assert_eq!(extract_domain(&xp, "2001-db8--1.sslip.io"), None);
+        assert_eq!(extract_domain(&xp, "foo.not-an-ip.sslip.io"), None);
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        for host in ["::1", "[::1]", "127.0.0.1", "localhost"] {
+            let listener = bind(host, 0, 1024).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            assert!(addr.ip().is_loopback(), "{}", addr);
+            TcpStream::connect(addr).await.unwrap();
+        }
+        assert!(bind("not a host", 0, 1024).await.is_err());
+
+        // a tiny backlog still lets connections through one at a time
+        let listener = bind("127.0.0.1", 0, 1).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_body_bytes() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--max-body-bytes",
+            "16",
+        ])
+        .await;
+
+        let response = send_raw(
+            addr,
+            "POST / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nContent-Length: 32\r\nConnection: close\r\n\r\n0123456789abcdef0123456789abcdef",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{}",
+            response
+        );
+
+        let response = send_raw(
+            addr,
+            "POST / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n10\r\n0123456789abcdef\r\n10\r\n0123456789abcdef\r\n0\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{}",
+            response
+        );
+
+        let response = send_raw(
+            addr,
+            "POST / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nContent-Length: 16\r\nConnection: close\r\n\r\n0123456789abcdef",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--rate-limit",
+            "2",
+        ])
+        .await;
+
+        let request = "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n";
+        for _ in 0..2 {
+            let response = send_raw(addr, request).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        }
+        let response = send_raw(addr, request).await;
+        assert!(
+            response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response);
+
+        for rate in ["0", "-1", "NaN", "inf", "fast"] {
+            assert!(
+                Args::try_parse_from(["http-proxy", "--rate-limit", rate]).is_err(),
+                "{}",
+                rate
+            );
+        }
+        // as a config file would give it
+        let mut args = Args::parse_from(["http-proxy"]);
+        args.rate_limit = Some(0.0);
+        assert!(State::new(args).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let backend = spawn_service(|req| async move {
+            let delay = if req.uri().path() == "/slow" { 500 } else { 0 };
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            Response::new(full("done"))
+        })
+        .await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--request-timeout-ms",
+            "200",
+        ])
+        .await;
+
+        let response = send_raw(
+            addr,
+            "GET /slow HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "{}",
+            response
+        );
+
+        let response = send_raw(
+            addr,
+            "GET /fast HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        async fn request_during_backend_restart(method: &str) -> String {
+            let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend = closed.local_addr().unwrap();
+            drop(closed);
+
+            let port = backend.port().to_string();
+            let addr = spawn_proxy(&[
+                "--backend-host",
+                "127.0.0.1",
+                "--backend-port",
+                &port,
+                "--max-retries",
+                "3",
+            ])
+            .await;
+
+            // the backend only comes up after the first connect attempt failed
+            let restarted = tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let listener = TcpListener::bind(backend).await.unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|_req: Request<hyper::body::Incoming>| async {
+                    Ok::<_, std::convert::Infallible>(Response::new(full("up")))
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(tokio_io::TokioIo::new(stream), service)
+                    .await;
+            });
+
+            let response = send_raw(
+                addr,
+                &format!(
+                    "{} / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    method
+                ),
+            )
+            .await;
+            restarted.abort();
+            response
+        }
+
+        let response = request_during_backend_restart("GET").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let response = request_during_backend_restart("POST").await;
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, x-secret".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("proxy-connection", "keep-alive".parse().unwrap());
+        headers.insert("x-secret", "1".parse().unwrap());
+        headers.insert("x-kept", "1".parse().unwrap());
+        strip_hop_by_hop(&mut headers, false);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-kept"], "1");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, Upgrade".parse().unwrap());
+        headers.insert("upgrade", "websocket".parse().unwrap());
+        headers.insert("proxy-connection", "keep-alive".parse().unwrap());
+        strip_hop_by_hop(&mut headers, true);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["connection"], "upgrade");
+        assert_eq!(headers["upgrade"], "websocket");
+        assert_eq!(get_upgrade_type(&headers), Some("websocket".to_string()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("te", "gzip, trailers".parse().unwrap());
+        headers.insert("trailer", "grpc-status".parse().unwrap());
+        strip_hop_by_hop(&mut headers, false);
+        assert_eq!(headers["te"], "trailers");
+        assert_eq!(headers["trailer"], "grpc-status");
+    }
+
+    #[tokio::test]
+    async fn test_hop_by_hop_not_forwarded() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nProxy-Connection: keep-alive\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(!response.contains("proxy-connection"), "{}", response);
+        assert!(!response.contains("\nconnection: close\n"), "{}", response);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let backend = spawn_backend("unix").await;
+        let path = std::env::temp_dir().join(format!("http-proxy-{}.sock", std::process::id()));
+        let args = Args::parse_from([
+            "http-proxy",
+            "--backend-port",
+            &backend.port().to_string(),
+            "--proxy-unix-socket",
+            path.to_str().unwrap(),
+        ]);
+        let listener = listener::bind_unix(args.proxy_unix_socket.as_ref().unwrap()).unwrap();
+        let state = SharedState::new(State::new(args).unwrap());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(serve(listener, state, shutdown_rx));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("x-backend: unix"), "{}", response);
+        assert!(!response.contains("x-forwarded-for"), "{}", response);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_systemd_socket_activation() {
+        use std::os::fd::{FromRawFd as _, IntoRawFd as _};
+
+        assert_eq!(listener::listen_fds(Some("42"), Some("2"), 42).unwrap(), 2);
+        assert!(listener::listen_fds(Some("41"), Some("1"), 42).is_err());
+        assert!(listener::listen_fds(Some("42"), Some("0"), 42).is_err());
+        assert!(listener::listen_fds(Some("42"), Some("x"), 42).is_err());
+        assert!(listener::listen_fds(None, None, 42).is_err());
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .into_raw_fd();
+        assert!(unsafe { listener::adopt(udp) }.is_err());
+        drop(unsafe { std::net::UdpSocket::from_raw_fd(udp) });
+
+        // a listener inherited as a bare descriptor, like systemd passes it
+        let backend = spawn_backend("systemd").await;
+        let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = inherited.local_addr().unwrap();
+        let listener = unsafe { listener::adopt(inherited.into_raw_fd()) }.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        let args = Args::parse_from([
+            "http-proxy",
+            "--backend-port",
+            &backend.port().to_string(),
+            "--systemd-socket-activation",
+        ]);
+        let state = SharedState::new(State::new(args).unwrap());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(serve(listener, state, shutdown_rx));
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("x-backend: systemd"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_compress() {
+        let backend = spawn_backend("plain").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port, "--compress"]).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(tokio_io::TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+
+        let req = Request::builder()
+            .uri("/")
+            .header("host", "foo.127.0.0.1.nip.io")
+            .header("accept-encoding", "br;q=1.0, gzip;q=0.8")
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-encoding"], "gzip");
+        assert!(!resp.headers().contains_key("content-length"));
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+            .unwrap();
+        assert!(
+            decoded.contains("accept-encoding: br;q=1.0, gzip;q=0.8\n"),
+            "{}",
+            decoded
+        );
+
+        let req = Request::builder()
+            .uri("/")
+            .header("host", "foo.127.0.0.1.nip.io")
+            .header("accept-encoding", "gzip;q=0")
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert!(!resp.headers().contains_key("content-encoding"));
+    }
+
+    #[tokio::test]
+    async fn test_compress_keeps_ranges() {
+        let backend = spawn_service(|req| async move {
+            let mut resp = Response::new(full("0123456789"));
+            let headers = resp.headers_mut();
+            match req.uri().path() {
+                "/range" => {
+                    *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+                    resp.headers_mut()
+                        .insert(CONTENT_RANGE, HeaderValue::from_static("bytes 0-9/100"));
+                }
+                "/no-transform" => {
+                    headers.insert("cache-control", HeaderValue::from_static("no-transform"));
+                }
+                _ => {
+                    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+                }
+            }
+            resp
+        })
+        .await;
+        let addr =
+            spawn_proxy(&["--backend-port", &backend.port().to_string(), "--compress"]).await;
+        let request = |path: &str| {
+            format!(
+                "GET {} HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+                path
+            )
+        };
+
+        let response = send_raw(addr, &request("/range")).await;
+        assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+        assert!(!response.contains("Content-Encoding"), "{}", response);
+        assert!(
+            response.contains("content-range: bytes 0-9/100\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\n0123456789"), "{}", response);
+
+        let response = send_raw(addr, &request("/no-transform")).await;
+        assert!(!response.contains("Content-Encoding"), "{}", response);
+        assert!(response.ends_with("0123456789"), "{}", response);
+
+        // the gzip body is not text
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request("/").as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(
+            response.contains("Content-Encoding: gzip\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("etag: W/\"v1\"\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_is_disconnect() {
+        // a backend hanging up before it answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(tokio_io::TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let req = Request::builder()
+            .uri("/")
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        let err = sender.send_request(req).await.unwrap_err();
+        assert!(is_disconnect(&err), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port().to_string();
+        drop(closed);
+
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--breaker-threshold",
+            "2",
+        ])
+        .await;
+        let request = "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n";
+        for _ in 0..2 {
+            let response = send_raw(addr, request).await;
+            assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+        }
+        let response = send_raw(addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let a = spawn_backend("a").await;
+        let b = spawn_backend("b").await;
+        let route = format!("web={},{}", a, b);
+        let addr = spawn_proxy(&["--route", &route]).await;
+
+        let mut backends = Vec::new();
+        for _ in 0..4 {
+            let response = send_raw(
+                addr,
+                "GET / HTTP/1.1\r\nHost: web.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            let backend = response
+                .lines()
+                .find_map(|line| line.strip_prefix("x-backend: "))
+                .unwrap()
+                .to_string();
+            backends.push(backend);
+        }
+        assert_eq!(backends, ["a", "b", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port, "--basic-auth", "alice:secret"]).await;
+        let request = |authorization: &str| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n{}Connection: close\r\n\r\n",
+                authorization
+            )
+        };
+
+        let response = send_raw(addr, &request("")).await;
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+        assert!(
+            response.contains("Www-Authenticate: Basic realm=\"http-proxy\"\r\n"),
+            "{}",
+            response
+        );
+
+        // base64 of alice:wrong
+        let response = send_raw(addr, &request("Authorization: Basic YWxpY2U6d3Jvbmc=\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+
+        // base64 of alice:secret
+        let response = send_raw(addr, &request("Authorization: Basic YWxpY2U6c2VjcmV0\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(!response.contains("authorization:"), "{}", response);
+
+        let response = send_raw(
+            addr,
+            "GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    #[test]
+    fn test_strip_path_prefix() {
+        let strip = |uri: &str, prefix| {
+            strip_path_prefix(&uri.parse().unwrap(), prefix).map(|uri| uri.to_string())
+        };
+        assert_eq!(
+            strip("/api/users?id=1", "/api").as_deref(),
+            Some("/users?id=1")
+        );
+        assert_eq!(strip("/api/users", "/api/").as_deref(), Some("/users"));
+        assert_eq!(strip("/api", "/api").as_deref(), Some("/"));
+        assert_eq!(strip("/api?x", "/api").as_deref(), Some("/?x"));
+        assert_eq!(
+            strip("http://example.com/api/a", "/api").as_deref(),
+            Some("http://example.com/a")
+        );
+        assert_eq!(strip("/apiary", "/api"), None);
+        assert_eq!(strip("/web/api", "/api"), None);
+    }
+
+    #[tokio::test]
+    async fn test_add_request_header() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--add-request-header",
+            "X-Env: staging",
+            "--add-request-header",
+            "x-token:abc",
+        ])
+        .await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nX-Env: prod\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.contains("x-env: staging\n"), "{}", response);
+        assert!(!response.contains("x-env: prod\n"), "{}", response);
+        assert!(response.contains("x-token: abc\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("X-Env: staging"),
+            Ok((
+                HeaderName::from_static("x-env"),
+                HeaderValue::from_static("staging")
+            ))
+        );
+        assert!(parse_header("X-Env").is_err());
+        assert!(parse_header("bad name:value").is_err());
+        assert!(parse_header("x-env:bad\nvalue").is_err());
+        assert!(Args::try_parse_from(["http-proxy", "--add-request-header", "nope"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_response_header() {
+        let backend = spawn_backend("default").await;
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = closed.local_addr().unwrap();
+        drop(closed);
+        let route = format!("down={}", down);
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--route",
+            &route,
+            "--add-response-header",
+            "X-Frame-Options: DENY",
+            "--add-response-header",
+            "x-backend: proxy",
+        ])
+        .await;
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        // header case follows whatever case the backend used
+        let response = response.to_lowercase();
+        assert!(
+            response.contains("x-frame-options: deny\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("x-backend: proxy\r\n"), "{}", response);
+        assert!(!response.contains("default"), "{}", response);
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: down.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+        assert!(
+            response.contains("X-Frame-Options: DENY\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_upgrade_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "connection",
+            HeaderValue::from_bytes(b"upgrade, \xff").unwrap(),
+        );
+        headers.insert("upgrade", HeaderValue::from_bytes(b"\xfe").unwrap());
+        assert_eq!(get_upgrade_type(&headers), None);
+
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port]).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(tokio_io::TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let req = Request::builder()
+            .uri("/")
+            .header("host", "foo.127.0.0.1.nip.io")
+            .header("connection", headers["connection"].clone())
+            .header("upgrade", headers["upgrade"].clone())
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port, "--max-connections", "1"]).await;
+        let request = b"GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n\r\n";
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(request).await.unwrap();
+        let mut buf = [0; 1024];
+        assert!(first.read(&mut buf).await.unwrap() > 0);
+
+        // accepted by the kernel but not served while the first one is open
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(request).await.unwrap();
+        assert!(timeout(Duration::from_millis(200), second.read(&mut buf))
+            .await
+            .is_err());
+
+        drop(first);
+        let n = timeout(Duration::from_secs(5), second.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"));
+    }
+
+    /// JSON log lines written on the current thread
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogBuffer {
+        fn set_default(&self) -> tracing::subscriber::DefaultGuard {
+            let writer = self.clone();
+            tracing::subscriber::set_default(
+                tracing_subscriber::registry()
+                    .with(log_layer(LogFormat::Json, move || writer.clone())),
+            )
+        }
+
+        fn events(&self) -> Vec<serde_json::Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_access_log() {
+        let buffer = LogBuffer::default();
+        let _guard = buffer.set_default();
+
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port]).await;
+        send_raw(
+            addr,
+            "GET /path HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+
+        let access_log = buffer
+            .events()
+            .into_iter()
+            .find(|event| event["fields"]["message"] == "request completed")
+            .unwrap();
+        assert_eq!(access_log["fields"]["method"], "GET");
+        assert_eq!(access_log["fields"]["path"], "/path");
+        assert_eq!(access_log["fields"]["status"], 200);
+        assert_eq!(
+            access_log["fields"]["backend"],
+            format!("localhost:{}", port)
+        );
+        assert_eq!(access_log["fields"]["client"], "127.0.0.1");
+        assert!(access_log["fields"]["elapsed_ms"].is_f64());
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port]).await;
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nX-Request-Id: abc-123\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .to_lowercase();
+        assert!(
+            response.contains("x-request-id: abc-123\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("x-request-id: abc-123\n"), "{}", response);
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .to_lowercase();
+        let echoed = response
+            .lines()
+            .find_map(|line| line.strip_prefix("x-request-id: "))
+            .unwrap();
+        assert!(Uuid::parse_str(echoed).is_ok(), "{}", response);
+        assert!(
+            response.contains(&format!("x-request-id: {}\n", echoed)),
+            "{}",
+            response
+        );
+
+        let addr = spawn_proxy(&["--backend-port", &port, "--request-id-header", ""]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .to_lowercase();
+        assert!(!response.contains("x-request-id"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port, "--accept-proxy-protocol"]).await;
+
+        let response = send_raw(
+            addr,
+            "PROXY TCP4 192.0.2.1 198.51.100.1 56324 8100\r\nGET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.contains("x-forwarded-for: 192.0.2.1\n"),
+            "{}",
+            response
+        );
+
+        // without a header the connection is closed without a response
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_timeout() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--accept-proxy-protocol",
+            "--max-connections",
+            "1",
+            "--handshake-timeout-ms",
+            "200",
+        ])
+        .await;
+
+        // a client that never sends the header only holds the one permit
+        // until the handshake timeout
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        let closed = timeout(Duration::from_secs(2), silent.read_to_end(&mut buf)).await;
+        assert!(closed.is_ok(), "connection left open");
+        assert!(buf.is_empty());
+
+        let response = timeout(
+            Duration::from_secs(2),
+            send_raw(
+                addr,
+                "PROXY TCP4 192.0.2.1 198.51.100.1 56324 8100\r\nGET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+            ),
+        )
+        .await
+        .expect("permit released");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_send_proxy_protocol() {
+        // answers with the client address from the PROXY header
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let source = proxy_protocol::read_header(&mut stream).await.unwrap();
+                    let service = service_fn(move |_req: Request<hyper::body::Incoming>| {
+                        let body = format!("{:?}", source.map(|addr| addr.ip()));
+                        async move { Ok::<_, std::convert::Infallible>(Response::new(full(body))) }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(tokio_io::TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--send-proxy-protocol",
+        ])
+        .await;
+        for _ in 0..2 {
+            let response = send_raw(
+                addr,
+                "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(
+                response.ends_with("\r\n\r\nSome(127.0.0.1)"),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_config() {
+        let check = |args: &[&str]| {
+            let args = Args::parse_from(
+                ["http-proxy", "--check-config"]
+                    .into_iter()
+                    .chain(args.iter().copied()),
+            );
+            async move { check_config(&args).await }
+        };
+        assert!(check(&[]).await.is_ok());
+        assert!(check(&["--proxy-host", "::1"]).await.is_ok());
+        assert!(check(&["--proxy-host", "no such host"]).await.is_err());
+        assert!(check(&[
+            "--tls-cert",
+            "/nonexistent.pem",
+            "--tls-key",
+            "/nonexistent.pem"
+        ])
+        .await
+        .is_err());
+        assert!(check(&["--request-id-header", "bad header"]).await.is_err());
+    }
+
+    #[test]
+    fn test_websocket_subprotocols() {
+        let mut request = HeaderMap::new();
+        request.insert("sec-websocket-protocol", "chat, superchat".parse().unwrap());
+        request.append("sec-websocket-protocol", "mqtt".parse().unwrap());
+        let requested = request_subprotocols(&request);
+        assert_eq!(requested, ["chat", "superchat", "mqtt"]);
+
+        let mut response = HeaderMap::new();
+        assert_eq!(unrequested_subprotocol(&requested, &response), None);
+        response.insert("sec-websocket-protocol", "superchat".parse().unwrap());
+        assert_eq!(unrequested_subprotocol(&requested, &response), None);
+        response.insert("sec-websocket-protocol", "graphql-ws".parse().unwrap());
+        assert_eq!(
+            unrequested_subprotocol(&requested, &response).as_deref(),
+            Some("graphql-ws")
+        );
+        assert_eq!(
+            unrequested_subprotocol(&[], &response).as_deref(),
+            Some("graphql-ws")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port, "--idle-timeout-ms", "200"]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1024];
+        let n = timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("idle connection was not closed")
+            .unwrap();
+        assert_eq!(n, 0);
+
+        // a keep-alive connection stays open while it is in use
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n\r\n")
+                .await
+                .unwrap();
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(buf[..n].starts_with(b"HTTP/1.1 200"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_pages() {
+        let dir =
+            std::env::temp_dir().join(format!("http-proxy-test-pages-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("502.html"), "<h1>backend unavailable</h1>").unwrap();
+        std::fs::write(dir.join("notes.txt"), "ignored").unwrap();
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port().to_string();
+        drop(closed);
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--error-page-dir",
+            dir.to_str().unwrap(),
+        ])
+        .await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+        assert!(
+            response.contains("Content-Type: text/html; charset=utf-8\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("\r\n\r\n<h1>backend unavailable</h1>"),
+            "{}",
+            response
+        );
+
+        // no page for 421, so the built-in body is kept
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 421"), "{}", response);
+        assert!(response.contains("text/plain"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_subdomain_header() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port]).await;
+        for (host, subdomain) in [
+            ("foo.192.168.1.1.nip.io", "foo"),
+            ("foo.bar.192.168.1.1.nip.io", "foo.bar"),
+        ] {
+            let response = send_raw(
+                addr,
+                &format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nX-Forwarded-Subdomain: spoofed\r\nConnection: close\r\n\r\n",
+                    host
+                ),
+            )
+            .await;
+            assert!(
+                response.contains(&format!("x-forwarded-subdomain: {}\n", subdomain)),
+                "{}",
+                response
+            );
+            assert!(!response.contains("spoofed"), "{}", response);
+        }
+
+        let addr = spawn_proxy(&["--backend-port", &port, "--subdomain-header", "X-Tenant"]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.contains("x-tenant: foo\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_ports() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let args = Args::parse_from([
+            "http-proxy",
+            "--proxy-host",
+            "127.0.0.1",
+            "--proxy-port",
+            "0",
+            "--proxy-port",
+            "0",
+            "--backend-port",
+            &port,
+        ]);
+        let listeners = bind_tcp(&args).await.unwrap();
+        assert_eq!(listeners.len(), 2);
+        let state = SharedState::new(State::new(args).unwrap());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut addrs = Vec::new();
+        for listener in listeners {
+            addrs.push(listener.local_addr().unwrap());
+            tokio::spawn(serve(listener, state.clone(), shutdown_rx.clone()));
+        }
+        assert_ne!(addrs[0], addrs[1]);
+
+        for addr in addrs {
+            let response = send_raw(
+                addr,
+                "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trailers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                head.extend_from_slice(&buf[..n]);
+            }
+            let head = String::from_utf8(head).unwrap().to_lowercase();
+            assert!(head.contains("\r\nte: trailers\r\n"), "{}", head);
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      Transfer-Encoding: chunked\r\n\
+                      Trailer: grpc-status\r\n\r\n\
+                      4\r\ndata\r\n0\r\ngrpc-status: 0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(tokio_io::TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let req = Request::builder()
+            .uri("/")
+            .header("host", "foo.127.0.0.1.nip.io")
+            .header("te", "trailers")
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        let body = resp.into_body().collect().await.unwrap();
+        assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+        assert_eq!(body.to_bytes(), "data");
+    }
+
+    #[tokio::test]
+    async fn test_host_rewrite() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        for (mode, expected) in [
+            ("suffix", "foo.localhost"),
+            ("preserve", "foo.192.168.1.1.nip.io"),
+            ("fixed:backend.internal", "backend.internal"),
+        ] {
+            let addr = spawn_proxy(&["--backend-port", &port, "--host-rewrite", mode]).await;
+            let response = send_raw(
+                addr,
+                "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(
+                response.contains(&format!("\nhost: {}\n", expected)),
+                "{}: {}",
+                mode,
+                response
+            );
+        }
+
+        assert!(Args::try_parse_from(["http-proxy", "--host-rewrite", "rewrite"]).is_err());
+        assert!(Args::try_parse_from(["http-proxy", "--host-rewrite", "fixed:a\nb"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_allow_subdomain() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--allow-subdomain",
+            "team-[a-z]+",
+            "--allow-subdomain",
+            "api",
+        ])
+        .await;
+        for (host, status) in [
+            ("team-a.192.168.1.1.nip.io", "200"),
+            ("api.192.168.1.1.nip.io", "200"),
+            ("team-1.192.168.1.1.nip.io", "403"),
+            ("x.api.192.168.1.1.nip.io", "403"),
+            ("apix.192.168.1.1.nip.io", "403"),
+        ] {
+            let response = send_raw(
+                addr,
+                &format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    host
+                ),
+            )
+            .await;
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {} ", status)),
+                "{}: {}",
+                host,
+                response
+            );
+        }
+
+        assert!(Args::try_parse_from(["http-proxy", "--allow-subdomain", "("]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_https() {
+        // nothing listens on the backend port, the redirect never needs it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        drop(listener);
+        let addr = spawn_proxy(&["--backend-port", &port, "--redirect-https"]).await;
+        for (host, location) in [
+            (
+                "foo.192.168.1.1.nip.io",
+                "https://foo.192.168.1.1.nip.io/a/b?c=d",
+            ),
+            (
+                "foo.192.168.1.1.nip.io:8100",
+                "https://foo.192.168.1.1.nip.io/a/b?c=d",
+            ),
+            ("[::1]:8100", "https://[::1]/a/b?c=d"),
+        ] {
+            let response = send_raw(
+                addr,
+                &format!(
+                    "GET /a/b?c=d HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    host
+                ),
+            )
+            .await;
+            assert!(
+                response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"),
+                "{}",
+                response
+            );
+            assert!(
+                response.contains(&format!("\r\nLocation: {}\r\n", location)),
+                "{}",
+                response
+            );
+        }
+
+        let response = send_raw(
+            addr,
+            "GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_backend_io_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-")
+                .await
+                .unwrap();
+            // stall with the connection open
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        let addr = spawn_proxy(&["--backend-port", &port, "--backend-io-timeout-ms", "200"]).await;
+
+        let response = timeout(
+            Duration::from_secs(5),
+            send_raw(
+                addr,
+                "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+            ),
+        )
+        .await
+        .unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backend_tls() {
+        use tokio_rustls::rustls::{pki_types::PrivateKeyDer, ServerConfig};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let service = service_fn(|_req: Request<hyper::body::Incoming>| async {
+                        Ok::<_, std::convert::Infallible>(Response::new(full("over tls")))
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(tokio_io::TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        let request = "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n";
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "localhost",
+            "--backend-port",
+            &port,
+            "--backend-tls",
+            "--backend-tls-insecure",
+        ])
+        .await;
+        let response = send_raw(addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("over tls"), "{}", response);
+
+        // the self-signed certificate is not trusted without the flag
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "localhost",
+            "--backend-port",
+            &port,
+            "--backend-tls",
+        ])
+        .await;
+        let response = send_raw(addr, request).await;
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_response_upgrade() {
+        let backend = Backend::Host("localhost".to_string(), 80);
+        let mut resp = Response::new(());
+        *resp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        assert!(response_upgrade(&mut resp, &backend).is_none());
+
+        let resp = upgrade_failed();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert!(resp.extensions().get::<ProxyError>().is_some());
+    }
+
+    /// peer that has already reset the connection
+    struct Reset;
+
+    impl AsyncRead for Reset {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl AsyncWrite for Reset {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_reset() {
+        let (client, mut peer) = tokio::io::duplex(64);
+        peer.write_all(b"hello").await.unwrap();
+        // returns instead of panicking when one side goes away abruptly
+        timeout(Duration::from_secs(5), tunnel(Reset, client))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_header_bytes() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port, "--max-header-bytes", "8192"]).await;
+
+        let request = |size| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nX-Big: {}\r\nConnection: close\r\n\r\n",
+                "a".repeat(size)
+            )
+        };
+        let response = send_raw(addr, &request(1000)).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // the proxy may answer and close before everything is written
+        let _ = stream.write_all(request(100_000).as_bytes()).await;
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            "{}",
+            response
+        );
+
+        assert!(Args::try_parse_from(["http-proxy", "--max-header-bytes", "100"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_default_subdomain() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let request = |host: &str| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                host
+            )
+        };
+
+        let addr = spawn_proxy(&["--backend-port", &port]).await;
+        let response = send_raw(addr, &request("192.168.1.1.nip.io")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"),
+            "{}",
+            response
+        );
+
+        let addr = spawn_proxy(&["--backend-port", &port, "--default-subdomain", "www"]).await;
+        let response = send_raw(addr, &request("192.168.1.1.nip.io")).await;
+        assert!(response.contains("\nhost: www.localhost\n"), "{}", response);
+        let response = send_raw(addr, &request("foo.192.168.1.1.nip.io")).await;
+        assert!(response.contains("\nhost: foo.localhost\n"), "{}", response);
+
+        let addr = spawn_proxy(&["--backend-port", &port, "--default-subdomain", ""]).await;
+        let response = send_raw(addr, &request("192.168.1.1.nip.io:8100")).await;
+        assert!(response.contains("\nhost: localhost\n"), "{}", response);
+
+        assert!(Args::try_parse_from(["http-proxy", "--default-subdomain", "a b"]).is_err());
+        assert!(Args::try_parse_from(["http-proxy", "--default-subdomain", "a..b"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--cors-allow-origin",
+            "http://localhost:3000",
+            "--basic-auth",
+            "user:secret",
+        ])
+        .await;
+
+        // answered by the proxy without credentials
+        let response = send_raw(
+            addr,
+            "OPTIONS /api HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n\
+             Origin: http://localhost:3000\r\n\
+             Access-Control-Request-Method: PUT\r\n\
+             Access-Control-Request-Headers: content-type, x-token\r\n\
+             Connection: close\r\n\r\n",
+        )
+        .await;
+        let response = response.to_lowercase();
+        assert!(
+            response.starts_with("http/1.1 204 no content\r\n"),
+            "{}",
+            response
+        );
+        assert!(!response.contains("x-backend"), "{}", response);
+        assert!(
+            response.contains("\r\naccess-control-allow-origin: http://localhost:3000\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\naccess-control-allow-methods: get, head, post, put, patch, delete, options\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\naccess-control-allow-headers: content-type, x-token\r\n"),
+            "{}",
+            response
+        );
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n\
+             Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\
+             Origin: http://localhost:3000\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let response = response.to_lowercase();
+        assert!(response.starts_with("http/1.1 200 ok\r\n"), "{}", response);
+        assert!(
+            response.contains("\r\nx-backend: default\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\naccess-control-allow-origin: http://localhost:3000\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("\r\nvary: origin\r\n"), "{}", response);
+
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n\
+             Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\
+             Origin: http://evil.example.com\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            !response
+                .to_lowercase()
+                .contains("access-control-allow-origin"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_log_bodies() {
+        let buffer = LogBuffer::default();
+        let _guard = buffer.set_default();
+
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-port", &port, "--log-bodies"]).await;
+        let body = r#"{"name":"small"}"#;
+        let response = send_raw(
+            addr,
+            &format!(
+                "POST / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let large = "x".repeat(LOG_BODY_LIMIT as usize + 1);
+        let response = send_raw(
+            addr,
+            &format!(
+                "POST / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                large.len(),
+                large
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let bodies = buffer
+            .events()
+            .into_iter()
+            .filter(|event| event["fields"]["message"] == "request body")
+            .map(|event| event["fields"]["body"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, vec![serde_json::Value::from(body)]);
+        // the echoed request headers
+        assert!(buffer
+            .events()
+            .iter()
+            .any(|event| event["fields"]["message"] == "response body"));
+    }
+
+    #[tokio::test]
+    async fn test_sticky_cookie() {
+        let first = spawn_backend("first").await;
+        let second = spawn_backend("second").await;
+        let route = format!("web={},{}", first, second);
+        let addr = spawn_proxy(&["--route", &route, "--sticky-cookie", "backend"]).await;
+        let request = |cookie: &str| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: web.127.0.0.1.nip.io\r\n{}Connection: close\r\n\r\n",
+                cookie
+            )
+        };
+        let served_by = |response: &str| {
+            ["first", "second"]
+                .into_iter()
+                .find(|name| response.contains(&format!("x-backend: {}\r\n", name)))
+                .unwrap()
+                .to_string()
+        };
+
+        let response = send_raw(addr, &request("")).await;
+        let backend = served_by(&response);
+        let set_cookie = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Set-Cookie: "))
+            .unwrap();
+        assert!(set_cookie.ends_with("; Path=/; HttpOnly"), "{}", set_cookie);
+        let cookie = set_cookie.split(';').next().unwrap();
+
+        // without the cookie the next request would go to the other backend
+        for _ in 0..3 {
+            let response = send_raw(addr, &request(&format!("Cookie: a=b; {}\r\n", cookie))).await;
+            assert_eq!(served_by(&response), backend);
+            assert!(!response.contains("Set-Cookie"), "{}", response);
+        }
+
+        let response = send_raw(addr, &request("Cookie: backend=7\r\n")).await;
+        assert!(response.contains("Set-Cookie: backend="), "{}", response);
+
+        assert!(Args::try_parse_from(["http-proxy", "--sticky-cookie", "a=b"]).is_err());
+    }
+
+    #[test]
+    fn test_suffixed_host() {
+        assert_eq!(
+            suffixed_host("foo.", "localhost", None).unwrap(),
+            "foo.localhost"
+        );
+        assert_eq!(
+            suffixed_host("foo.", "localhost", Some("8888")).unwrap(),
+            "foo.localhost:8888"
+        );
+        assert_eq!(
+            suffixed_host("foo\n.", "localhost", None).unwrap_err(),
+            "foo\n.localhost"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_rewritten_host() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr =
+            spawn_proxy(&["--backend-port", &port, "--domain-suffix", "local\x7fhost"]).await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let (backend, connections) = spawn_counting_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--maintenance",
+            "--maintenance-body",
+            "back soon",
+            "--maintenance-retry-after",
+            "120",
+        ])
+        .await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nRetry-After: 120\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\nback soon"), "{}", response);
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+
+        let response = send_raw(
+            addr,
+            "GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let first = spawn_backend("first").await;
+        let second = spawn_backend("second").await;
+        let path =
+            std::env::temp_dir().join(format!("http-proxy-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, format!("route = [\"web={}\"]\n", first)).unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(["http-proxy", "--config", path.to_str().unwrap()])
+            .unwrap();
+        let state = SharedState::new(State::new(config::load(&matches).unwrap()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(serve(listener, state.clone(), shutdown_rx));
+        let request = "GET / HTTP/1.1\r\nHost: web.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n";
+
+        let response = send_raw(addr, request).await;
+        assert!(response.contains("x-backend: first\r\n"), "{}", response);
+
+        std::fs::write(&path, format!("route = [\"web={}\"]\n", second)).unwrap();
+        reload(&matches, &state).unwrap();
+        let response = send_raw(addr, request).await;
+        assert!(response.contains("x-backend: second\r\n"), "{}", response);
+
+        // a broken file leaves the running configuration alone
+        std::fs::write(&path, "route = [\"web=\"]\n").unwrap();
+        assert!(reload(&matches, &state).is_err());
+        let response = send_raw(addr, request).await;
+        assert!(response.contains("x-backend: second\r\n"), "{}", response);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_maintenance() {
+        let args = || Args::parse_from(["http-proxy"]);
+        let state = State::new(args()).unwrap();
+        // as SIGUSR1 flips it
+        state.maintenance.fetch_xor(true, Ordering::Relaxed);
+        let state = state.reload(args()).unwrap();
+        assert!(state.maintenance.load(Ordering::Relaxed));
+
+        // a configuration changing it wins
+        let state = State::new(Args::parse_from(["http-proxy", "--maintenance"])).unwrap();
+        state.maintenance.fetch_xor(true, Ordering::Relaxed);
+        let state = state.reload(args()).unwrap();
+        assert!(!state.maintenance.load(Ordering::Relaxed));
+        let state = state
+            .reload(Args::parse_from(["http-proxy", "--maintenance"]))
+            .unwrap();
+        assert!(state.maintenance.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_preserve_port() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let request = |host: &str| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                host
+            )
+        };
+
+        let addr = spawn_proxy(&["--backend-port", &port]).await;
+        let response = send_raw(addr, &request("foo.192.168.1.1.nip.io:8888")).await;
+        assert!(response.contains("\nhost: foo.localhost\n"), "{}", response);
+
+        let addr = spawn_proxy(&["--backend-port", &port, "--preserve-port"]).await;
+        let response = send_raw(addr, &request("foo.192.168.1.1.nip.io:8888")).await;
+        assert!(
+            response.contains("\nhost: foo.localhost:8888\n"),
+            "{}",
+            response
+        );
+        let response = send_raw(addr, &request("foo.192.168.1.1.nip.io")).await;
+        assert!(response.contains("\nhost: foo.localhost\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_header_case() {
+        // answers with the raw request head, so its casing is visible
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    head.extend_from_slice(&buf[..n]);
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nx-RESPONSE-case: 1\r\nContent-Length: {}\r\n\r\n",
+                    head.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.write_all(&head).await.unwrap();
+            }
+        });
+        let request = "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\n\
+                       x-REQUEST-case: 1\r\nConnection: close\r\n\r\n";
+
+        for (case, request_header, forwarded, response_header) in [
+            (
+                "preserve",
+                "\r\nx-REQUEST-case: 1\r\n",
+                "\r\nX-Forwarded-For: ",
+                "\r\nx-RESPONSE-case: 1\r\n",
+            ),
+            (
+                "title",
+                "\r\nX-Request-Case: 1\r\n",
+                "\r\nX-Forwarded-For: ",
+                "\r\nX-Response-Case: 1\r\n",
+            ),
+            (
+                "lower",
+                "\r\nx-request-case: 1\r\n",
+                "\r\nx-forwarded-for: ",
+                "\r\nx-response-case: 1\r\n",
+            ),
+        ] {
+            let addr = spawn_proxy(&[
+                "--backend-host",
+                "127.0.0.1",
+                "--backend-port",
+                &port,
+                "--header-case",
+                case,
+            ])
+            .await;
+            let response = send_raw(addr, request).await;
+            assert!(response.contains(request_header), "{}: {}", case, response);
+            assert!(response.contains(forwarded), "{}: {}", case, response);
+            assert!(response.contains(response_header), "{}: {}", case, response);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", echo_addr);
+
+        // without the flag CONNECT is just an unknown host
+        let addr = spawn_proxy(&[]).await;
+        let response = send_raw(
+            addr,
+            &request.replace("\r\n\r\n", "\r\nConnection: close\r\n\r\n"),
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"),
+            "{}",
+            response
+        );
+
+        let addr = spawn_proxy(&["--enable-connect"]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            head.extend_from_slice(&buf[..n]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        let response = send_raw(
+            addr,
+            "CONNECT 127.0.0.1 HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_limits() {
+        let backend = spawn_service(|_| async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Response::new(full("done"))
+        })
+        .await;
+        let port = backend.port().to_string();
+        let slow = format!("slow={};timeout=1000", backend);
+        let small = format!("small={};max-body=4", backend);
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--request-timeout-ms",
+            "100",
+            "--route",
+            &slow,
+            "--route",
+            &small,
+        ])
+        .await;
+        let request = |subdomain: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: {}.192.168.1.1.nip.io\r\n\
+                 Content-Length: 8\r\nConnection: close\r\n\r\n12345678",
+                subdomain
+            )
+        };
+
+        let response = send_raw(addr, &request("slow")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let response = send_raw(addr, &request("small")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{}",
+            response
+        );
+        // everything else is still bound by the global timeout
+        let response = send_raw(addr, &request("other")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_otlp_span() {
+        use opentelemetry::trace::{SpanKind, TraceId};
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(telemetry::layer(&provider)),
+        );
+
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--otlp-endpoint",
+            "http://127.0.0.1:4318/v1/traces",
+        ])
+        .await;
+        let response = send_raw(
+            addr,
+            "GET /path HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\n\
+             traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\r\n\
+             Connection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 1, "{:?}", spans);
+        let span = &spans[0];
+        assert_eq!(span.name, "GET");
+        assert_eq!(span.span_kind, SpanKind::Server);
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+        assert_eq!(span.parent_span_id.to_string(), "00f067aa0ba902b7");
+        let attribute = |key: &str| {
+            span.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.to_string())
+        };
+        assert_eq!(attribute("http.request.method").as_deref(), Some("GET"));
+        assert_eq!(attribute("url.path").as_deref(), Some("/path"));
+        assert_eq!(
+            attribute("http.response.status_code").as_deref(),
+            Some("200")
+        );
+        assert_eq!(attribute("backend"), Some(format!("localhost:{}", port)));
+        assert!(attribute("elapsed_ms").is_some());
+
+        // the backend continues the trace below the proxy's span
+        let traceparent = format!(
+            "traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01\n",
+            span.span_context.span_id()
+        );
+        assert!(response.contains(&traceparent), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_upload() {
+        const CHUNK: usize = 64 * 1024;
+        const CHUNKS: usize = 256;
+        // more than this in flight means the proxy is holding on to the body
+        const WINDOW: usize = 4 * 1024 * 1024;
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let counted = received.clone();
+        let backend = spawn_service(move |req| {
+            let received = counted.clone();
+            async move {
+                let mut body = req.into_body();
+                while let Some(frame) = body.frame().await {
+                    if let Ok(data) = frame.unwrap().into_data() {
+                        received.fetch_add(data.len(), Ordering::Relaxed);
+                    }
+                }
+                Response::new(full(received.load(Ordering::Relaxed).to_string()))
+            }
+        })
+        .await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /upload HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\n\
+                  Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let chunk = format!("{:x}\r\n{}\r\n", CHUNK, "x".repeat(CHUNK));
+        for sent in (1..=CHUNKS).map(|i| i * CHUNK) {
+            stream.write_all(chunk.as_bytes()).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while received.load(Ordering::Relaxed) + WINDOW < sent {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .expect("the backend did not receive the body as it was sent");
+        }
+        stream.write_all(b"0\r\n\r\n").await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.ends_with(&format!("\r\n\r\n{}", CHUNK * CHUNKS)),
+            "{}",
+            response
+        );
+    }
+
+    /// listener failing with `errors` before accepting from `inner`
+    struct FailingListener {
+        inner: TcpListener,
+        errors: std::sync::Mutex<Vec<std::io::Error>>,
+    }
+
+    impl listener::Listener for FailingListener {
+        type Stream = TcpStream;
+
+        async fn accept(&self) -> std::io::Result<(TcpStream, Option<SocketAddr>)> {
+            if let Some(err) = self.errors.lock().unwrap().pop() {
+                return Err(err);
+            }
+            let (stream, peer) = self.inner.accept().await?;
+            Ok((stream, Some(peer)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors() {
+        let serve_with = |errors: Vec<std::io::Error>| async move {
+            let inner = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = inner.local_addr().unwrap();
+            let listener = FailingListener {
+                inner,
+                errors: std::sync::Mutex::new(errors),
+            };
+            let args = Args::parse_from(["http-proxy"]);
+            let state = SharedState::new(State::new(args).unwrap());
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let server = tokio::spawn(serve(listener, state, shutdown_rx));
+            (addr, server, shutdown_tx)
+        };
+
+        // EMFILE, or WSAEMFILE on Windows
+        let emfile = if cfg!(windows) { 10024 } else { 24 };
+        let transient = vec![
+            std::io::Error::from(std::io::ErrorKind::ConnectionAborted),
+            std::io::Error::from_raw_os_error(emfile),
+        ];
+        let (addr, server, _shutdown_tx) = serve_with(transient).await;
+        let response = send_raw(
+            addr,
+            "GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(!server.is_finished());
+
+        let fatal = vec![std::io::Error::from(std::io::ErrorKind::InvalidInput)];
+        let (_, server, _shutdown_tx) = serve_with(fatal).await;
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("serving stops on a fatal accept error")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let backend = spawn_service(move |req| {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                let cache_control = match req.uri().path() {
+                    "/private" => "private, max-age=60",
+                    _ => "public, max-age=60",
+                };
+                Response::builder()
+                    .header("cache-control", cache_control)
+                    .body(full(format!("response {}", count)))
+                    .unwrap()
+            }
+        })
+        .await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--cache-size-bytes",
+            "65536",
+        ])
+        .await;
+        let get = |path: &str, extra: &str| {
+            format!(
+                "GET {} HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\n{}Connection: close\r\n\r\n",
+                path, extra
+            )
+        };
+
+        // a miss goes to the backend and stores the response, which the
+        // next request is served
+        let response = send_raw(addr, &get("/public", "")).await;
+        assert!(response.ends_with("\r\n\r\nresponse 1"), "{}", response);
+        let response = send_raw(addr, &get("/public", "")).await;
+        assert!(response.ends_with("\r\n\r\nresponse 1"), "{}", response);
+        assert!(response.contains("\r\nAge: 0\r\n"), "{}", response);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // other paths are misses, private responses never stored
+        let response = send_raw(addr, &get("/other", "")).await;
+        assert!(response.ends_with("\r\n\r\nresponse 2"), "{}", response);
+        send_raw(addr, &get("/private", "")).await;
+        let response = send_raw(addr, &get("/private", "")).await;
+        assert!(response.ends_with("\r\n\r\nresponse 4"), "{}", response);
+
+        // no-store goes to the backend and leaves the cache as it was
+        let response = send_raw(addr, &get("/public", "Cache-Control: no-store\r\n")).await;
+        assert!(response.ends_with("\r\n\r\nresponse 5"), "{}", response);
+        let response = send_raw(addr, &get("/public", "")).await;
+        assert!(response.ends_with("\r\n\r\nresponse 1"), "{}", response);
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() {
+        let backend = spawn_service(|req| async move {
+            let expect = req.headers().get(hyper::header::EXPECT).cloned();
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            Response::new(full(format!("expect: {:?}, body: {:?}", expect, body)))
+        })
+        .await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"PUT /upload HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\n\
+                  Content-Length: 5\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        // the body is only sent once the proxy asks for it
+        let mut interim = vec![0; "HTTP/1.1 100 Continue\r\n\r\n".len()];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut interim))
+            .await
+            .expect("no interim response")
+            .unwrap();
+        assert_eq!(interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.ends_with("\r\n\r\nexpect: Some(\"100-continue\"), body: b\"hello\""),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_status() {
+        let backend = spawn_service(|req| async move {
+            let mut resp = Response::new(full("backend exploded"));
+            if req.uri().path() == "/fail" {
+                *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+            resp
+        })
+        .await;
+        let port = backend.port().to_string();
+        let request = |path: &str| {
+            format!(
+                "GET {} HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+                path
+            )
+        };
+
+        let plain = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+        let response = send_raw(plain, &request("/fail")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{}",
+            response
+        );
+
+        let dir =
+            std::env::temp_dir().join(format!("http-proxy-test-rewrite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("503.html"), "<h1>try again later</h1>").unwrap();
+        let rewriting = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--rewrite-status",
+            "500:503",
+        ])
+        .await;
+        let with_page = spawn_proxy(&[
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+            "--rewrite-status",
+            "500:503",
+            "--error-page-dir",
+            dir.to_str().unwrap(),
+        ])
+        .await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let response = send_raw(rewriting, &request("/fail")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("\r\n\r\nbackend exploded"),
+            "{}",
+            response
+        );
+        let response = send_raw(rewriting, &request("/")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let response = send_raw(with_page, &request("/fail")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("\r\n\r\n<h1>try again later</h1>"),
+            "{}",
+            response
+        );
+
+        assert!(Args::try_parse_from(["http-proxy", "--rewrite-status", "500"]).is_err());
+        assert!(Args::try_parse_from(["http-proxy", "--rewrite-status", "500:100"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_trust_forwarded() {
+        let buffer = LogBuffer::default();
+        let _guard = buffer.set_default();
+
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let args = ["--backend-port", &port, "--rate-limit", "1"];
+        let untrusted = spawn_proxy(&args).await;
+        let trusted = spawn_proxy(&[&args[..], &["--trust-forwarded"]].concat()).await;
+        let request = |forwarded: &str| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nForwarded: {}\r\nConnection: close\r\n\r\n",
+                forwarded
+            )
+        };
+        let clients = || {
+            buffer
+                .events()
+                .into_iter()
+                .filter(|event| event["fields"]["message"] == "request completed")
+                .map(|event| event["fields"]["client"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let response = send_raw(trusted, &request("for=1.2.3.4")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("forwarded: for=1.2.3.4, for=127.0.0.1;proto=http\n"),
+            "{}",
+            response
+        );
+        // every forwarded client gets its own rate limit
+        let response = send_raw(trusted, &request("for=\"5.6.7.8:4711\"")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let response = send_raw(trusted, &request("for=1.2.3.4")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+            "{}",
+            response
+        );
+        assert_eq!(clients(), ["1.2.3.4", "5.6.7.8", "1.2.3.4"]);
+
+        let response = send_raw(untrusted, &request("for=1.2.3.4")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("forwarded: for=127.0.0.1;proto=http\n"),
+            "{}",
+            response
+        );
+        let response = send_raw(untrusted, &request("for=5.6.7.8")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+            "{}",
+            response
+        );
+        assert_eq!(clients()[3..], ["127.0.0.1", "127.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn test_expose_error_detail() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port().to_string();
+        drop(closed);
+        // accepts connections, then drops them without answering
+        let hangup = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hangup_port = hangup.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = hangup.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+            }
+        });
+        let request = "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n";
+
+        for (port, detail) in [
+            (&closed_port, "connect failed: connection refused"),
+            (
+                &hangup_port,
+                "request failed: connection closed before message completed",
+            ),
+        ] {
+            let args = ["--backend-host", "127.0.0.1", "--backend-port", port];
+            let plain = spawn_proxy(&args).await;
+            let response = send_raw(plain, request).await;
+            assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+            assert!(!response.contains("X-Proxy-Error"), "{}", response);
+
+            let exposing = spawn_proxy(&[&args[..], &["--expose-error-detail"]].concat()).await;
+            let response = send_raw(exposing, request).await;
+            assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+            assert!(
+                response.contains(&format!("\r\nX-Proxy-Error: {}\r\n", detail)),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dashed_ip_host() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--ip-separator",
+            ".",
+            "--ip-separator",
+            "-",
+        ])
+        .await;
+
+        for host in ["foo.127-0-0-1.nip.io", "foo.127.0.0.1.nip.io"] {
+            let response = send_raw(
+                addr,
+                &format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    host
+                ),
+            )
+            .await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.contains("host: foo.localhost"), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_depth() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--max-connections",
+            "1",
+            "--queue-depth",
+            "1",
+        ])
+        .await;
+        let request = b"GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n\r\n";
+        let mut buf = [0; 1024];
+
+        // keeps the only worker busy
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(request).await.unwrap();
+        assert!(first.read(&mut buf).await.unwrap() > 0);
+
+        let mut queued = TcpStream::connect(addr).await.unwrap();
+        queued.write_all(request).await.unwrap();
+        assert!(timeout(Duration::from_millis(200), queued.read(&mut buf))
+            .await
+            .is_err());
+
+        // the queue is full now
+        for _ in 0..2 {
+            let response = send_raw(addr, std::str::from_utf8(request).unwrap()).await;
+            assert!(
+                response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+                "{}",
+                response
+            );
+            assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response);
+        }
+
+        drop(first);
+        let n = timeout(Duration::from_secs(5), queued.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"));
+
+        assert!(Args::try_parse_from(["http-proxy", "--queue-depth", "1"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_queue_depth_shutdown() {
+        let args = Args::parse_from([
+            "http-proxy",
+            "--max-connections",
+            "2",
+            "--queue-depth",
+            "1",
+            "--shutdown-grace-ms",
+            "3000",
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve(
+            listener,
+            SharedState::new(State::new(args).unwrap()),
+            shutdown_rx,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // idle workers stop with the accept loop rather than at the grace period
+        shutdown_tx.send(true).unwrap();
+        timeout(Duration::from_millis(500), server)
+            .await
+            .expect("shut down within the grace period")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_suffix_map() {
+        let backend = spawn_backend("default").await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--suffix-map",
+            "app=internal",
+            "--suffix-map",
+            "admin=admin.internal",
+        ])
+        .await;
+
+        for (host, backend_host) in [
+            ("foo.app.127.0.0.1.nip.io", "foo.internal"),
+            ("foo.admin.127.0.0.1.nip.io", "foo.admin.internal"),
+            ("foo.127.0.0.1.nip.io", "foo.localhost"),
+        ] {
+            let response = send_raw(
+                addr,
+                &format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    host
+                ),
+            )
+            .await;
+            assert!(
+                response.contains(&format!("\nhost: {}\n", backend_host)),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::Addr(listener.local_addr().unwrap());
+        let state = |args: &[&str]| {
+            State::new(Args::parse_from(
+                std::iter::once("http-proxy").chain(args.iter().copied()),
+            ))
+            .unwrap()
+        };
+
+        let stream = connect_backend(&state(&[]), &backend).await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+
+        let stream = connect_backend(&state(&["--tcp-nodelay"]), &backend)
+            .await
+            .unwrap();
+        assert!(stream.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unrequested_upgrade() {
+        // switches protocols whatever the request asked for
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        head.extend_from_slice(&buf[..n]);
+                    }
+                    stream
+                        .write_all(
+                            b"HTTP/1.1 101 Switching Protocols\r\n\
+                              Connection: upgrade\r\nUpgrade: websocket\r\n\r\n",
+                        )
+                        .await
+                        .unwrap();
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+        let addr = spawn_proxy(&["--backend-host", "127.0.0.1", "--backend-port", &port]).await;
+
+        for request in [
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\n\
+             Connection: upgrade, close\r\nUpgrade: h2c\r\n\r\n",
+        ] {
+            let response = send_raw(addr, request).await;
+            assert!(
+                response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_wait_metrics() {
+        let buffer = LogBuffer::default();
+        let _guard = buffer.set_default();
+
+        let backend = spawn_service(|_| async { Response::new(full(vec![b'x'; 16 << 20])) }).await;
+        let port = backend.port().to_string();
+        let args = Args::parse_from([
+            "http-proxy",
+            "--backend-host",
+            "127.0.0.1",
+            "--backend-port",
+            &port,
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = SharedState::new(State::new(args).unwrap());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(serve(listener, state.clone(), shutdown_rx));
+        let client_wait_sum = || {
+            state
+                .current()
+                .metrics
+                .render()
+                .lines()
+                .find_map(|line| {
+                    line.strip_prefix("http_proxy_response_body_client_wait_seconds_sum ")
+                })
+                .unwrap()
+                .parse::<f64>()
+                .unwrap()
+        };
+        assert_eq!(client_wait_sum(), 0.0);
+
+        // a client that only starts reading after a while
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: foo.192.168.1.1.nip.io\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        drop(stream);
+
+        // recorded once the proxy saw the end of the body
+        let waited = timeout(Duration::from_secs(5), async {
+            loop {
+                let waited = client_wait_sum();
+                if waited > 0.0 {
+                    break waited;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(waited >= 0.2, "{}", waited);
+
+        let access_log = buffer
+            .events()
+            .into_iter()
+            .find(|event| event["fields"]["message"] == "request completed")
+            .unwrap();
+        assert!(
+            access_log["fields"]["client_wait_ms"].as_f64().unwrap() >= 200.0,
+            "{}",
+            access_log
+        );
+        assert!(access_log["fields"]["backend_wait_ms"].is_f64());
+    }
+
+    #[tokio::test]
+    async fn test_pool_wait_timeout() {
+        let backend = spawn_service(|_| async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Response::new(full("done"))
+        })
+        .await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--max-connections-per-host",
+            "2",
+            "--pool-wait-timeout-ms",
+            "100",
+        ])
+        .await;
+        let request = "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n";
+
+        let slow = (0..2)
+            .map(|_| tokio::spawn(send_raw(addr, request)))
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let started = Instant::now();
+        let response = send_raw(addr, request).await;
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        for slow in slow {
+            let response = slow.await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        }
+        // the slots are free again once the responses are done
+        let response = send_raw(addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_parse_content_route() {
+        let (media_type, backend) =
+            parse_content_route("Application/GRPC=127.0.0.1:50051").unwrap();
+        assert_eq!(media_type, "application/grpc");
+        assert!(matches!(backend, Backend::Addr(_)));
+        let (_, backend) = parse_content_route("text/*=grpc.internal:80").unwrap();
+        assert_eq!(backend.to_string(), "grpc.internal:80");
+        assert!(parse_content_route("application/grpc").is_err());
+        assert!(parse_content_route("grpc=127.0.0.1:50051").is_err());
+        assert!(parse_content_route("*/*=127.0.0.1:50051").is_err());
+        assert!(parse_content_route("text/html=127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_content_route() {
+        let routes = ["application/grpc=127.0.0.1:1", "text/*=127.0.0.1:2"]
+            .map(|route| parse_content_route(route).unwrap());
+        let route = |headers: &[(HeaderName, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.append(name, HeaderValue::from_static(value));
+            }
+            content_route(&map, &routes).map(|backend| backend.to_string())
+        };
+        let first = Some("127.0.0.1:1".to_string());
+        let second = Some("127.0.0.1:2".to_string());
+        assert_eq!(route(&[(CONTENT_TYPE, "application/grpc")]), first);
+        assert_eq!(route(&[(CONTENT_TYPE, "Application/gRPC+proto")]), first);
+        assert_eq!(route(&[(CONTENT_TYPE, "application/grpc-web")]), None);
+        assert_eq!(
+            route(&[(CONTENT_TYPE, "text/plain; charset=utf-8")]),
+            second
+        );
+        assert_eq!(route(&[(ACCEPT, "image/png, text/html;q=0.9")]), second);
+        // accept only counts without a content-type
+        assert_eq!(
+            route(&[(CONTENT_TYPE, "application/json"), (ACCEPT, "text/html")]),
+            None
+        );
+        assert_eq!(route(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_content_routes() {
+        let grpc = spawn_backend("grpc").await;
+        let web = spawn_backend("web").await;
+        let grpc_route = format!("application/grpc={}", grpc);
+        let web_route = format!("api={}", web);
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &web.port().to_string(),
+            "--content-route",
+            &grpc_route,
+            "--route",
+            &web_route,
+        ])
+        .await;
+        let request = |host: &str, content_type: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: {}.127.0.0.1.nip.io\r\nContent-Type: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                host, content_type
+            )
+        };
+
+        let response = send_raw(addr, &request("foo", "application/grpc+proto")).await;
+        assert!(response.contains("x-backend: grpc"), "{}", response);
+        let response = send_raw(addr, &request("foo", "application/json")).await;
+        assert!(response.contains("x-backend: web"), "{}", response);
+        // the subdomain's own route wins
+        let response = send_raw(addr, &request("api", "application/grpc")).await;
+        assert!(response.contains("x-backend: web"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_route_methods() {
+        let backend = spawn_backend("static").await;
+        let route = format!("static={};methods=GET,HEAD", backend);
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &backend.port().to_string(),
+            "--route",
+            &route,
+        ])
+        .await;
+        let request = |method: &str, subdomain: &str| {
+            format!(
+                "{} / HTTP/1.1\r\nHost: {}.127.0.0.1.nip.io\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                method, subdomain
+            )
+        };
+
+        let response = send_raw(addr, &request("GET", "static")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("x-backend: static"), "{}", response);
+
+        let response = send_raw(addr, &request("POST", "static")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nAllow: GET, HEAD\r\n"),
+            "{}",
+            response
+        );
+        // other subdomains take every method
+        let response = send_raw(addr, &request("POST", "other")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        assert_eq!(
+            parse_route("api=127.0.0.1:3000;methods=GET").map(|route| route.limits.methods),
+            Ok(Some(vec![Method::GET]))
+        );
+        assert!(parse_route("api=127.0.0.1:3000;methods=GET,").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shutdown_signal() {
+        assert_eq!(
+            parse_signal("SIGUSR2").unwrap(),
+            SignalKind::user_defined2()
+        );
+        assert_eq!(parse_signal("term").unwrap(), SignalKind::terminate());
+        assert_eq!(parse_signal("12").unwrap(), SignalKind::from_raw(12));
+        assert!(parse_signal("SIGNOPE").is_err());
+        assert!(parse_signal("0").is_err());
+        assert!(Args::try_parse_from(["http-proxy", "--no-default-shutdown-signals"]).is_err());
+
+        let args = Args::parse_from([
+            "http-proxy",
+            "--shutdown-signal",
+            "SIGUSR2",
+            "--no-default-shutdown-signals",
+        ]);
+        let signals = shutdown_signals(&args).unwrap();
+        assert_eq!(signals.len(), 1);
+        let mut shutdown = tokio::spawn(shutdown_signal(signals));
+        assert!(timeout(Duration::from_millis(100), &mut shutdown)
+            .await
+            .is_err());
+
+        let status = std::process::Command::new("kill")
+            .args(["-USR2", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        timeout(Duration::from_secs(5), shutdown)
+            .await
+            .expect("shut down on SIGUSR2")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pick_encoding() {
+        let pick = |accept: &'static str, offered: &[CompressAlgo]| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(accept));
+            pick_encoding(&headers, offered)
+        };
+        let both = [CompressAlgo::Br, CompressAlgo::Gzip];
+        assert_eq!(pick("gzip, br", &both), Some(CompressAlgo::Br));
+        assert_eq!(pick("gzip, br;q=0.5", &both), Some(CompressAlgo::Gzip));
+        assert_eq!(
+            pick("br;q=1.0, gzip;q=0.8", &[CompressAlgo::Gzip]),
+            Some(CompressAlgo::Gzip)
+        );
+        assert_eq!(pick("GZIP", &both), Some(CompressAlgo::Gzip));
+        assert_eq!(pick("*", &both), Some(CompressAlgo::Br));
+        assert_eq!(pick("br;q=0, *", &both), Some(CompressAlgo::Gzip));
+        assert_eq!(pick("gzip;q=0", &[CompressAlgo::Gzip]), None);
+        assert_eq!(pick("deflate", &both), None);
+        assert_eq!(pick_encoding(&HeaderMap::new(), &both), None);
+    }
+
+    #[tokio::test]
+    async fn test_compress_algo() {
+        let backend = spawn_service(|req| async move {
+            let body = if req.uri().path() == "/tiny" {
+                "ok".to_string()
+            } else {
+                "hello ".repeat(100)
+            };
+            Response::new(full(body))
+        })
+        .await;
+        let port = backend.port().to_string();
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &port,
+            "--compress",
+            "--compress-algo",
+            "br",
+            "--compress-algo",
+            "gzip",
+            "--compress-level",
+            "9",
+            "--compress-min-bytes",
+            "64",
+        ])
+        .await;
+        let get = |path: &'static str, accept: &'static str| async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (mut sender, conn) =
+                hyper::client::conn::http1::handshake(tokio_io::TokioIo::new(stream))
+                    .await
+                    .unwrap();
+            tokio::spawn(conn);
+            let req = Request::builder()
+                .uri(path)
+                .header("host", "foo.127.0.0.1.nip.io")
+                .header("accept-encoding", accept)
+                .body(http_body_util::Empty::<Bytes>::new())
+                .unwrap();
+            let resp = sender.send_request(req).await.unwrap();
+            let encoding = resp
+                .headers()
+                .get(CONTENT_ENCODING)
+                .map(|value| value.to_str().unwrap().to_owned());
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            (encoding, body)
+        };
+
+        let (encoding, body) = get("/", "gzip, br").await;
+        assert_eq!(encoding.as_deref(), Some("br"));
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(
+            &mut brotli::Decompressor::new(&body[..], 4096),
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(decoded, "hello ".repeat(100));
+
+        let (encoding, body) = get("/", "gzip, br;q=0.5").await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "hello ".repeat(100));
+
+        // too small to be worth it
+        let (encoding, body) = get("/tiny", "gzip, br").await;
+        assert_eq!(encoding, None);
+        assert_eq!(body, "ok");
+
+        assert!(
+            Args::try_parse_from(["http-proxy", "--compress", "--compress-level", "10"]).is_err()
+        );
+        assert!(Args::try_parse_from(["http-proxy", "--compress-algo", "br"]).is_err());
+    }
+
+    #[test]
+    fn test_extract_domain_pathological() {
+        let xp = domain_regex(&["nip.io".to_string(), "sslip.io".to_string()], &['.', '-']);
+        for host in [
+            format!("{}.nip.io", "a-".repeat(20_000) + "a"),
+            format!("{}.nip.io", "-".repeat(40_000)),
+            format!("{}1.2.3.4.nip.io", "a.".repeat(20_000)),
+        ] {
+            let started = std::time::Instant::now();
+            assert!(extract_domain(&xp, &host).is_none());
+            assert!(
+                started.elapsed() < Duration::from_millis(100),
+                "{:?}",
+                started.elapsed()
+            );
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_extract_domain_any_host(host in "\\PC{0,300}") {
+            let xp = domain_regex(&["nip.io".to_string(), "sslip.io".to_string()], &['.', '-', '_']);
+            let _ = extract_subdomain(&xp, &host);
+        }
+
+        #[test]
+        fn test_extract_domain_adversarial(
+            labels in proptest::collection::vec("[a-z0-9-]{0,70}", 0..8),
+            ip in "[0-9a-f:.-]{0,45}",
+            suffix in "(nip\\.io|sslip\\.io|nip\\.com)\\.?(:[0-9]{0,6})?",
+        ) {
+            let xp = domain_regex(&["nip.io".to_string(), "sslip.io".to_string()], &['.', '-', '_']);
+            let host = format!("{}.{}.{}", labels.join("."), ip, suffix);
+            if let Some((domain, _)) = extract_subdomain(&xp, &host) {
+                proptest::prop_assert!(host.to_ascii_lowercase().starts_with(domain.trim_end_matches('.')));
+            }
+        }
+
+        #[test]
+        fn test_extract_domain_valid(
+            subdomain in "[a-z][a-z0-9]{0,20}(\\.[a-z][a-z0-9]{0,20}){0,3}",
+            ip in proptest::prelude::any::<[u8; 4]>(),
+        ) {
+            let xp = domain_regex(&["nip.io".to_string()], &['.']);
+            let host = format!("{}.{}.{}.{}.{}.nip.io", subdomain, ip[0], ip[1], ip[2], ip[3]);
+            proptest::prop_assert_eq!(
+                extract_subdomain(&xp, &host),
+                Some((format!("{}.", subdomain), Some(IpAddr::from(ip))))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_absolute_form_host() {
+        let backend = spawn_backend("default").await;
+        let addr = spawn_proxy(&["--backend-port", &backend.port().to_string()]).await;
+
+        for request in [
+            "GET http://foo.127.0.0.1.nip.io/ HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+            "GET http://FOO.127.0.0.1.nip.io./ HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+            "GET http://foo.127.0.0.1.nip.io:8080/ HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io:8080\r\nConnection: close\r\n\r\n",
+        ] {
+            let response = send_raw(addr, request).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(
+                response.contains("x-forwarded-host: foo.127.0.0.1.nip.io"),
+                "{}",
+                response
+            );
+        }
+
+        for request in [
+            "GET http://bar.127.0.0.1.nip.io/ HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+            "GET http://foo.127.0.0.1.nip.io:8080/ HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        ] {
+            let response = send_raw(addr, request).await;
+            assert!(
+                response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{}",
+                response
+            );
+            assert!(
+                response.ends_with("host header does not match the request target\n"),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_canary() {
+        let (subdomain, addr, percent) = parse_canary("web=127.0.0.1:80@10%").unwrap();
+        assert_eq!(subdomain, "web");
+        assert_eq!(addr, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(percent, 10.0);
+        assert_eq!(parse_canary("web=127.0.0.1:80@2.5").unwrap().2, 2.5);
+        assert!(parse_canary("web=127.0.0.1:80").is_err());
+        assert!(parse_canary("127.0.0.1:80@10%").is_err());
+        assert!(parse_canary("web=127.0.0.1:80@101%").is_err());
+        assert!(parse_canary("web=127.0.0.1@10%").is_err());
+        assert_eq!(parse_canary("api.v2=127.0.0.1:80@10%").unwrap().0, "api.v2");
+        for subdomain in ["", "Web", "a..b", ".web", "we b", "*"] {
+            let canary = format!("{}=127.0.0.1:80@10%", subdomain);
+            assert!(parse_canary(&canary).is_err(), "{:?}", subdomain);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_canary() {
+        let stable = spawn_backend("stable").await;
+        let canary = spawn_backend("canary").await;
+        let route = format!("web={}", stable);
+        let canary_route = format!("web={}@25%", canary);
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &stable.port().to_string(),
+            "--route",
+            &route,
+            "--canary",
+            &canary_route,
+            "--canary-header",
+        ])
+        .await;
+
+        let requests = 400;
+        let mut canaries = 0;
+        for _ in 0..requests {
+            let response = send_raw(
+                addr,
+                "GET / HTTP/1.1\r\nHost: web.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            if response.contains("x-backend: canary") {
+                assert!(response.contains("X-Canary: true"), "{}", response);
+                canaries += 1;
+            } else {
+                assert!(response.contains("x-backend: stable"), "{}", response);
+                assert!(response.contains("X-Canary: false"), "{}", response);
+            }
+        }
+        // 25% of 400 is 100, with a standard deviation of about 9
+        assert!((60..=140).contains(&canaries), "{} canaries", canaries);
+
+        // other subdomains never see the canary or the header
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: api.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.contains("x-backend: stable"), "{}", response);
+        assert!(!response.contains("X-Canary"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_remove_headers() {
+        let backend = spawn_backend("default").await;
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &backend.port().to_string(),
+            "--remove-request-header",
+            "Origin",
+            "--remove-request-header",
+            "x-tracking",
+            "--remove-request-header",
+            "x-env",
+            "--add-request-header",
+            "x-env:staging",
+            "--remove-response-header",
+            "X-Backend",
+        ])
+        .await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nOrigin: https://example.com\r\nX-Tracking: 1\r\nX-Env: prod\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(!response.contains("origin:"), "{}", response);
+        assert!(!response.contains("x-tracking:"), "{}", response);
+        assert!(response.contains("accept: */*\n"), "{}", response);
+        assert!(response.contains("x-env: staging\n"), "{}", response);
+        assert!(!response.contains("x-backend"), "{}", response);
+
+        assert!(
+            Args::try_parse_from(["http-proxy", "--remove-request-header", "bad name"]).is_err()
+        );
+        assert!(Args::try_parse_from(["http-proxy", "--remove-response-header", ""]).is_err());
+        assert!(Args::try_parse_from(["http-proxy", "--remove-request-header", "Host"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_headers_keeps_own() {
+        let backend = spawn_backend("default").await;
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &backend.port().to_string(),
+            "--trusted-proxies",
+            "127.0.0.1",
+            "--remove-request-header",
+            "x-forwarded-for",
+            "--remove-request-header",
+            "x-request-id",
+            "--remove-response-header",
+            "x-request-id",
+        ])
+        .await;
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\nX-Forwarded-For: 192.0.2.1\r\nX-Request-Id: from-client\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        // the client's address list is gone, the proxy's entry is not
+        assert!(
+            response.contains("x-forwarded-for: 127.0.0.1\n"),
+            "{}",
+            response
+        );
+        assert!(!response.contains("192.0.2.1"), "{}", response);
+        assert!(!response.contains("from-client"), "{}", response);
+        assert!(response.contains("x-request-id: "), "{}", response);
+        assert!(response.contains("X-Request-Id: "), "{}", response);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_route() {
+        let path = std::env::temp_dir().join(format!("http-proxy-{}-app.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|req: Request<hyper::body::Incoming>| async move {
+                    Ok::<_, BoxError>(Response::new(full(format!(
+                        "{} {}",
+                        req.method(),
+                        req.uri()
+                    ))))
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(tokio_io::TokioIo::new(stream), service),
+                );
+            }
+        });
+        let route = format!("app=unix:{};methods=GET", path.display());
+        let missing = format!("gone=unix:{}.missing", path.display());
+        let addr = spawn_proxy(&["--route", &route, "--route", &missing]).await;
+
+        let response = send_raw(
+            addr,
+            "GET /hello HTTP/1.1\r\nHost: app.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("GET /hello"), "{}", response);
+        let response = send_raw(
+            addr,
+            "POST / HTTP/1.1\r\nHost: app.127.0.0.1.nip.io\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+
+        std::fs::remove_file(&path).unwrap();
+        let response = send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: gone.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+
+        let parsed = parse_route("app=unix:/run/app.sock").unwrap();
+        assert_eq!(parsed.socket, Some(PathBuf::from("/run/app.sock")));
+        let mixed = Args::parse_from([
+            "http-proxy",
+            "--route",
+            "app=unix:/run/app.sock",
+            "--route",
+            "app=127.0.0.1:80",
+        ]);
+        assert!(State::new(mixed).is_err());
+        for flag in ["--backend-tls", "--send-proxy-protocol"] {
+            let args = Args::parse_from(["http-proxy", "--route", "app=unix:/run/app.sock", flag]);
+            assert!(State::new(args).is_err(), "{}", flag);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_route_retries() {
+        let path =
+            std::env::temp_dir().join(format!("http-proxy-{}-retry.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let route = format!("app=unix:{}", path.display());
+        let addr = spawn_proxy(&["--route", &route, "--max-retries", "3"]).await;
+
+        let request = tokio::spawn(send_raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: app.127.0.0.1.nip.io\r\nConnection: close\r\n\r\n",
+        ));
+        // the socket shows up during the first backoff
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(|_req: Request<hyper::body::Incoming>| async move {
+            Ok::<_, BoxError>(Response::new(full("up")))
+        });
+        tokio::spawn(
+            http1::Builder::new().serve_connection(tokio_io::TokioIo::new(stream), service),
+        );
+
+        let response = request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("up"), "{}", response);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_deadline_millis() {
+        let now = Instant::now();
+        let wall = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let deadline = now + Duration::from_millis(2500);
+        assert_eq!(deadline_millis(deadline, now, wall), 1_700_000_002_500);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(incoming_deadline(&headers, now, wall), None);
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("1700000002500"));
+        assert_eq!(incoming_deadline(&headers, now, wall), Some(deadline));
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("1699999999000"));
+        assert!(incoming_deadline(&headers, now, wall).unwrap() <= now);
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("soon"));
+        assert_eq!(incoming_deadline(&headers, now, wall), None);
+    }
+
+    #[tokio::test]
+    async fn test_propagate_deadline() {
+        let backend = spawn_backend("default").await;
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &backend.port().to_string(),
+            "--request-timeout-ms",
+            "5000",
+            "--propagate-deadline",
+        ])
+        .await;
+        let unix_millis = || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64
+        };
+        let forwarded = |response: &str| {
+            response
+                .lines()
+                .find_map(|line| line.strip_prefix("x-request-deadline: "))
+                .map(|millis| millis.parse::<u64>().unwrap())
+        };
+        let request = |deadline: Option<u64>| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: foo.127.0.0.1.nip.io\r\n{}Connection: close\r\n\r\n",
+                deadline.map_or(String::new(), |millis| format!(
+                    "X-Request-Deadline: {}\r\n",
+                    millis
+                ))
+            )
+        };
+
+        // the configured timeout from when the request came in
+        let before = unix_millis();
+        let response = send_raw(addr, &request(None)).await;
+        let after = unix_millis();
+        let deadline = forwarded(&response).expect("deadline forwarded");
+        assert!(
+            (before + 5000..=after + 5000).contains(&deadline),
+            "{} not within {}..={}",
+            deadline,
+            before + 5000,
+            after + 5000
+        );
+
+        // an earlier deadline from the client wins, a later one does not
+        let earlier = unix_millis() + 1000;
+        let response = send_raw(addr, &request(Some(earlier))).await;
+        let deadline = forwarded(&response).expect("deadline forwarded");
+        assert!(
+            deadline.abs_diff(earlier) <= 1,
+            "{} != {}",
+            deadline,
+            earlier
+        );
+        let later = unix_millis() + 60_000;
+        let response = send_raw(addr, &request(Some(later))).await;
+        assert!(
+            forwarded(&response).unwrap() < later - 50_000,
+            "{}",
+            response
+        );
+
+        let response = send_raw(addr, &request(Some(unix_millis() - 1000))).await;
+        assert!(response.starts_with("HTTP/1.1 504"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_timeout() {
+        use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+
+        let host = "foo.127.0.0.1.nip.io";
+        let cert = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "http-proxy-test-tls-handshake-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        let backend = spawn_backend("default").await;
+        let addr = spawn_proxy(&[
+            "--backend-port",
+            &backend.port().to_string(),
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+            "--max-connections",
+            "1",
+            "--handshake-timeout-ms",
+            "200",
+        ])
+        .await;
+
+        // never sends a ClientHello, so only the timeout frees the permit
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        let closed = timeout(Duration::from_secs(2), silent.read_to_end(&mut buf)).await;
+        assert!(closed.is_ok(), "connection left open");
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(host).unwrap(), stream);
+        let mut stream = timeout(Duration::from_secs(2), handshake)
+            .await
+            .expect("permit released")
+            .unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    host
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,259 @@
+use crate::endpoint;
+use hyper::StatusCode;
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::watch};
+
+/// upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    /// responses by status class, 1xx to 5xx
+    responses: [AtomicU64; 5],
+    connect_failures: AtomicU64,
+    open_connections: AtomicU64,
+    latency: Histogram,
+    /// time response bodies waited for the backend and for the client
+    backend_wait: Histogram,
+    client_wait: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// non-cumulative counts per bucket, the last one being +Inf
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn request_started(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn response_sent(&self, status: StatusCode) {
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize;
+        self.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_failed(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count a client connection as open until the guard is dropped
+    pub fn connection_opened(self: &Arc<Self>) -> OpenConnection {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self.clone())
+    }
+
+    pub fn open_connections(&self) -> u64 {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn observe_latency(&self, elapsed: Duration) {
+        self.latency.observe(elapsed);
+    }
+
+    pub fn observe_body_waits(&self, backend: Duration, client: Duration) {
+        self.backend_wait.observe(backend);
+        self.client_wait.observe(client);
+    }
+
+    /// prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP http_proxy_requests_total Requests received by the proxy.\n");
+        out.push_str("# TYPE http_proxy_requests_total counter\n");
+        writeln!(
+            out,
+            "http_proxy_requests_total {}",
+            self.requests.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out.push_str("# HELP http_proxy_responses_total Responses sent, by status class.\n");
+        out.push_str("# TYPE http_proxy_responses_total counter\n");
+        for (i, count) in self.responses.iter().enumerate() {
+            writeln!(
+                out,
+                "http_proxy_responses_total{{class=\"{}xx\"}} {}",
+                i + 1,
+                count.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP http_proxy_backend_connect_failures_total Failed or timed out backend connects.\n",
+        );
+        out.push_str("# TYPE http_proxy_backend_connect_failures_total counter\n");
+        writeln!(
+            out,
+            "http_proxy_backend_connect_failures_total {}",
+            self.connect_failures.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out.push_str("# HELP http_proxy_open_connections Client connections being served.\n");
+        out.push_str("# TYPE http_proxy_open_connections gauge\n");
+        writeln!(
+            out,
+            "http_proxy_open_connections {}",
+            self.open_connections()
+        )
+        .unwrap();
+
+        out.push_str(
+            "# HELP http_proxy_request_duration_seconds Time until the response head was ready.\n",
+        );
+        out.push_str("# TYPE http_proxy_request_duration_seconds histogram\n");
+        self.latency
+            .render(&mut out, "http_proxy_request_duration_seconds");
+
+        out.push_str(
+            "# HELP http_proxy_response_body_backend_wait_seconds Time response bodies waited for data from the backend.\n",
+        );
+        out.push_str("# TYPE http_proxy_response_body_backend_wait_seconds histogram\n");
+        self.backend_wait
+            .render(&mut out, "http_proxy_response_body_backend_wait_seconds");
+
+        out.push_str(
+            "# HELP http_proxy_response_body_client_wait_seconds Time response bodies waited for the client to take data.\n",
+        );
+        out.push_str("# TYPE http_proxy_response_body_client_wait_seconds histogram\n");
+        self.client_wait
+            .render(&mut out, "http_proxy_response_body_client_wait_seconds");
+
+        out
+    }
+}
+
+/// a client connection counted in `open_connections`
+pub struct OpenConnection(Arc<Metrics>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// the bucket, sum and count lines of the histogram `name`
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            match LATENCY_BUCKETS.get(i) {
+                Some(le) => writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative),
+                None => writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative),
+            }
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        )
+        .unwrap();
+        writeln!(out, "{}_count {}", name, cumulative).unwrap();
+    }
+}
+
+/// serve `/metrics` until shutdown is requested
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>, shutdown: watch::Receiver<bool>) {
+    let respond = move |path: &str| {
+        (path == "/metrics").then(|| ("text/plain; version=0.0.4", metrics.render()))
+    };
+    endpoint::serve(listener, "metrics", respond, shutdown).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.request_started();
+        metrics.request_started();
+        let _open = metrics.connection_opened();
+        drop(metrics.connection_opened());
+        metrics.response_sent(StatusCode::OK);
+        metrics.response_sent(StatusCode::BAD_GATEWAY);
+        metrics.connect_failed();
+        metrics.observe_latency(Duration::from_millis(20));
+        metrics.observe_latency(Duration::from_secs(30));
+        metrics.observe_body_waits(Duration::from_millis(3), Duration::from_millis(400));
+
+        let text = metrics.render();
+        assert!(text.contains("\nhttp_proxy_requests_total 2\n"), "{}", text);
+        assert!(text.contains("\nhttp_proxy_responses_total{class=\"2xx\"} 1\n"));
+        assert!(text.contains("\nhttp_proxy_responses_total{class=\"4xx\"} 0\n"));
+        assert!(text.contains("\nhttp_proxy_responses_total{class=\"5xx\"} 1\n"));
+        assert!(text.contains("\nhttp_proxy_backend_connect_failures_total 1\n"));
+        assert!(text.contains("\nhttp_proxy_open_connections 1\n"));
+        assert!(text.contains("\nhttp_proxy_request_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("\nhttp_proxy_request_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("\nhttp_proxy_request_duration_seconds_bucket{le=\"10\"} 1\n"));
+        assert!(text.contains("\nhttp_proxy_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("\nhttp_proxy_request_duration_seconds_sum 30.02\n"));
+        assert!(text.contains("\nhttp_proxy_request_duration_seconds_count 2\n"));
+        assert!(text
+            .contains("\nhttp_proxy_response_body_backend_wait_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(
+            text.contains("\nhttp_proxy_response_body_client_wait_seconds_bucket{le=\"0.25\"} 0\n")
+        );
+        assert!(text.contains("\nhttp_proxy_response_body_client_wait_seconds_sum 0.4\n"));
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        metrics.request_started();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve(listener, metrics, shutdown_rx));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("\nhttp_proxy_requests_total 1\n"),
+            "{}",
+            response
+        );
+
+        shutdown_tx.send(true).unwrap();
+        server.await.unwrap();
+    }
+}
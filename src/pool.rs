@@ -0,0 +1,108 @@
+use hyper::client::conn::http1::SendRequest;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{error::Elapsed, timeout};
+
+/// keep-alive connections to the backends, keyed by backend address
+pub struct Pool<B> {
+    max_idle_per_host: usize,
+    max_per_host: Option<usize>,
+    idle: Mutex<HashMap<String, Vec<SendRequest<B>>>>,
+    /// free slots for requests in flight, per backend
+    slots: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl<B> Pool<B>
+where
+    B: Send + 'static,
+{
+    pub fn new(max_idle_per_host: usize, max_per_host: Option<usize>) -> Self {
+        Self {
+            max_idle_per_host,
+            max_per_host,
+            idle: Mutex::new(HashMap::new()),
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// a slot for a request to `key`, waiting up to `wait` for one of the
+    /// `max_per_host` to free up; `None` if there is no limit
+    pub async fn acquire(
+        &self,
+        key: &str,
+        wait: Duration,
+    ) -> Result<Option<OwnedSemaphorePermit>, Elapsed> {
+        let Some(max) = self.max_per_host else {
+            return Ok(None);
+        };
+        let slots = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(max)))
+            .clone();
+        let permit = timeout(wait, slots.acquire_owned()).await?;
+        // the semaphore is never closed
+        Ok(Some(permit.unwrap()))
+    }
+
+    /// take an idle connection to `key` that can accept a request right away
+    pub fn checkout(&self, key: &str) -> Option<SendRequest<B>> {
+        let mut idle = self.idle.lock().unwrap();
+        let senders = idle.get_mut(key)?;
+        while let Some(sender) = senders.pop() {
+            if sender.is_ready() {
+                return Some(sender);
+            }
+        }
+        None
+    }
+
+    /// hand a connection back once the response in flight on it is finished;
+    /// connections that get closed in the meantime are dropped
+    pub fn release(self: &Arc<Self>, key: String, mut sender: SendRequest<B>) {
+        if self.max_idle_per_host == 0 {
+            return;
+        }
+
+        let pool = self.clone();
+        tokio::spawn(async move {
+            if sender.ready().await.is_err() {
+                return;
+            }
+            let mut idle = pool.idle.lock().unwrap();
+            let senders = idle.entry(key).or_default();
+            if senders.len() < pool.max_idle_per_host {
+                senders.push(sender);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::Empty;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let wait = Duration::from_millis(50);
+        let unlimited = Pool::<Empty<Bytes>>::new(8, None);
+        assert!(unlimited.acquire("a", wait).await.unwrap().is_none());
+
+        let pool = Pool::<Empty<Bytes>>::new(8, Some(1));
+        let first = pool.acquire("a", wait).await.unwrap();
+        assert!(first.is_some());
+        assert!(pool.acquire("a", wait).await.is_err());
+        // other backends have slots of their own
+        assert!(pool.acquire("b", wait).await.unwrap().is_some());
+        drop(first);
+        assert!(pool.acquire("a", wait).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// longest possible v1 header, including the trailing CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// read a PROXY protocol v1 or v2 header off the front of `stream`, returning
+/// the original client address; `None` when the sender did not relay one (v1
+/// `UNKNOWN`, v2 `LOCAL` or a non-IP address family)
+///
+/// nothing past the header is consumed, so the stream can be handed on as is
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: [u8; 12],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source address"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid(
+                    "PROXY protocol v1 address does not match its family",
+                ));
+            }
+            let port = source_port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len @ ..] = header;
+    let mut addresses = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_command {
+        // LOCAL: a health check of the load balancer itself
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 version or command")),
+    }
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x1 | 0x2 => Err(invalid("truncated PROXY protocol v2 addresses")),
+        _ => Ok(None),
+    }
+}
+
+/// PROXY protocol v2 header relaying a connection from `source` to
+/// `destination`; without a source, e.g. for Unix socket clients, the header
+/// carries the LOCAL command
+pub fn encode_v2(source: Option<SocketAddr>, destination: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let Some(source) = source else {
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        return header;
+    };
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        // mixed families are both sent as IPv6, with IPv4 addresses mapped
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+            header.extend_from_slice(&v6(src).octets());
+            header.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read(mut input: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let result = read_header(&mut input).await;
+        (result, input)
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (addr, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(addr.unwrap(), None);
+
+        assert!(read(b"PROXY TCP4 2001:db8::1 2001:db8::2 4000 80\r\n")
+            .await
+            .0
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1\r\n").await.0.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.0.is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat())
+            .await
+            .0
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&443u16.to_be_bytes());
+        header.extend_from_slice(b"GET /");
+        let (addr, rest) = read(&header).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        header.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&[0; 16]);
+        header.extend_from_slice(&4000u16.to_be_bytes());
+        header.extend_from_slice(&80u16.to_be_bytes());
+        let (addr, _) = read(&header).await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read(&local).await.0.unwrap(), None);
+
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend_from_slice(&[0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert!(read(&truncated).await.0.is_err());
+
+        let mut bad_version = V2_SIGNATURE.to_vec();
+        bad_version.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        assert!(read(&bad_version).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encode_v2() {
+        let v4 = "192.0.2.1:56324".parse().unwrap();
+        let v6 = "[2001:db8::1]:4000".parse().unwrap();
+        let destination = "198.51.100.1:80".parse().unwrap();
+
+        let header = encode_v2(Some(v4), destination);
+        assert_eq!(header.len(), 28);
+        let (addr, rest) = read(&header).await;
+        assert_eq!(addr.unwrap(), Some(v4));
+        assert!(rest.is_empty());
+        let header = encode_v2(Some(v6), destination);
+        assert_eq!(read(&header).await.0.unwrap(), Some(v6));
+        let header = encode_v2(Some(v4), v6);
+        assert_eq!(
+            read(&header).await.0.unwrap(),
+            Some("[::ffff:192.0.2.1]:56324".parse().unwrap())
+        );
+        assert_eq!(read(&encode_v2(None, destination)).await.0.unwrap(), None);
+    }
+}
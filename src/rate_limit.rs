@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// buckets are only pruned once there are more clients than this
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// token bucket rate limiter keyed by client address; every client may burst
+/// up to one second worth of requests
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> Self {
+        Self {
+            rate: requests_per_second,
+            burst: requests_per_second.max(1.0),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// take a token for `ip`, or return how long until one is available
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            // a full bucket behaves exactly like a missing one
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(TokenBucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            // a rate small enough to overflow means never in practice
+            Err(
+                Duration::try_from_secs_f64((1.0 - bucket.tokens) / self.rate)
+                    .unwrap_or(Duration::MAX),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(2.0);
+        let ip = "10.0.0.1".parse().unwrap();
+        let other = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.check_at(ip, start).is_ok());
+        assert!(limiter.check_at(ip, start).is_ok());
+        let retry = limiter.check_at(ip, start).unwrap_err();
+        assert_eq!(retry, Duration::from_millis(500));
+        assert!(limiter.check_at(other, start).is_ok());
+
+        assert!(limiter
+            .check_at(ip, start + Duration::from_millis(500))
+            .is_ok());
+        assert!(limiter
+            .check_at(ip, start + Duration::from_millis(500))
+            .is_err());
+    }
+
+    #[test]
+    fn test_tiny_rate() {
+        let limiter = RateLimiter::new(f64::MIN_POSITIVE);
+        let ip = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        assert!(limiter.check_at(ip, start).is_ok());
+        assert_eq!(limiter.check_at(ip, start), Err(Duration::MAX));
+    }
+}
@@ -0,0 +1,251 @@
+use hyper::Method;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// subdomain served by one or more backends, given on the command line as
+/// `web=10.0.0.1:80,10.0.0.2:80@3`, optionally followed by limits such as
+/// `;timeout=2000;max-body=1048576;methods=GET,HEAD`, or by a local server
+/// on a Unix socket, as `app=unix:/run/app.sock`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub subdomain: String,
+    pub targets: Vec<Target>,
+    /// the socket serving the route, which then has no targets
+    pub socket: Option<PathBuf>,
+    pub limits: Limits,
+}
+
+/// overrides of `--request-timeout-ms` and `--max-body-bytes` for a route,
+/// and the methods it takes if not all of them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    pub timeout_ms: Option<u64>,
+    pub max_body_bytes: Option<usize>,
+    pub methods: Option<Vec<Method>>,
+}
+
+impl Limits {
+    /// these limits, with those `other` sets taking precedence
+    pub fn or(self, other: Limits) -> Limits {
+        Limits {
+            timeout_ms: other.timeout_ms.or(self.timeout_ms),
+            max_body_bytes: other.max_body_bytes.or(self.max_body_bytes),
+            methods: other.methods.or(self.methods),
+        }
+    }
+}
+
+/// backend of a route receiving `weight` out of every `total weight` requests
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub addr: SocketAddr,
+    pub weight: u32,
+}
+
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}=", self.subdomain)?;
+        if let Some(socket) = &self.socket {
+            write!(f, "unix:{}", socket.display())?;
+        }
+        for (i, target) in self.targets.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", target.addr)?;
+            if target.weight != 1 {
+                write!(f, "@{}", target.weight)?;
+            }
+        }
+        if let Some(timeout_ms) = self.limits.timeout_ms {
+            write!(f, ";timeout={}", timeout_ms)?;
+        }
+        if let Some(max_body_bytes) = self.limits.max_body_bytes {
+            write!(f, ";max-body={}", max_body_bytes)?;
+        }
+        if let Some(methods) = &self.limits.methods {
+            write!(f, ";methods=")?;
+            for (i, method) in methods.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                write!(f, "{}", method)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// where the requests for a subdomain go
+#[derive(Debug)]
+pub enum Upstream {
+    Balancer(Balancer),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// weighted round-robin over the targets of a route
+#[derive(Debug)]
+pub struct Balancer {
+    targets: Vec<Target>,
+    total_weight: usize,
+    next: AtomicUsize,
+    /// turns each target has had, taken or not
+    turns: Vec<AtomicUsize>,
+}
+
+impl Balancer {
+    pub fn new(targets: Vec<Target>) -> Self {
+        assert!(!targets.is_empty(), "a route needs at least one target");
+        let total_weight = targets.iter().map(|target| target.weight as usize).sum();
+        Self {
+            turns: targets.iter().map(|_| AtomicUsize::new(0)).collect(),
+            targets,
+            total_weight,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// the target at `index` in the order they were given
+    pub fn get(&self, index: usize) -> Option<SocketAddr> {
+        self.targets.get(index).map(|target| target.addr)
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.targets.iter().map(|target| target.addr)
+    }
+
+    pub fn index_of(&self, addr: SocketAddr) -> Option<usize> {
+        self.targets.iter().position(|target| target.addr == addr)
+    }
+
+    /// the next target in turn, moving on to the following ones if it is not
+    /// usable; when none are, the one whose turn it was
+    ///
+    /// `share` is the fraction of its turns a target takes, 0 for unusable
+    /// ones and less than 1 for those still slow-starting
+    pub fn pick(&self, share: impl Fn(SocketAddr) -> f64) -> SocketAddr {
+        let mut slot = self.next.fetch_add(1, Ordering::Relaxed) % self.total_weight;
+        let first = self
+            .targets
+            .iter()
+            .position(|target| {
+                let weight = target.weight as usize;
+                if slot < weight {
+                    return true;
+                }
+                slot -= weight;
+                false
+            })
+            .unwrap();
+        let addr = self.targets[first].addr;
+        let turn = self.turns[first].fetch_add(1, Ordering::Relaxed) as f64;
+        let share_of_first = share(addr);
+        if ((turn + 1.0) * share_of_first).floor() > (turn * share_of_first).floor() {
+            return addr;
+        }
+        (1..self.targets.len())
+            .map(|i| self.targets[(first + i) % self.targets.len()].addr)
+            .find(|addr| share(*addr) > 0.0)
+            .unwrap_or(addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn target(port: u16, weight: u32) -> Target {
+        Target {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_weighted() {
+        let balancer = Balancer::new(vec![target(1, 2), target(2, 1)]);
+        let ports = (0..6)
+            .map(|_| balancer.pick(|_| 1.0).port())
+            .collect::<Vec<_>>();
+        assert_eq!(ports, vec![1, 1, 2, 1, 1, 2]);
+    }
+
+    #[test]
+    fn test_skip_unusable() {
+        let balancer = Balancer::new(vec![target(1, 1), target(2, 1), target(3, 1)]);
+        let ports = (0..3)
+            .map(|_| balancer.pick(|addr| (addr.port() != 2) as u8 as f64).port())
+            .collect::<Vec<_>>();
+        assert_eq!(ports, vec![1, 3, 3]);
+        assert_eq!(balancer.pick(|_| 0.0).port(), 1);
+    }
+
+    #[test]
+    fn test_slow_start() {
+        let balancer = Balancer::new(vec![target(1, 1), target(2, 1)]);
+        // the second target recovers, its share growing by a tenth every 20
+        // requests
+        let picked = (0..200)
+            .map(|i| {
+                let ramp = (i / 20) as f64 / 10.0;
+                balancer.pick(|addr| if addr.port() == 2 { ramp } else { 1.0 })
+            })
+            .collect::<Vec<_>>();
+        let to_second = picked
+            .chunks(20)
+            .map(|chunk| chunk.iter().filter(|addr| addr.port() == 2).count())
+            .collect::<Vec<_>>();
+        assert_eq!(to_second[0], 0);
+        assert!(
+            to_second.windows(2).all(|w| w[0] <= w[1]),
+            "{:?}",
+            to_second
+        );
+        assert!(to_second[5] > 0 && to_second[5] < 10, "{:?}", to_second);
+    }
+
+    #[test]
+    fn test_index() {
+        let balancer = Balancer::new(vec![target(1, 1), target(2, 1)]);
+        assert_eq!(balancer.get(1), Some(target(2, 1).addr));
+        assert_eq!(balancer.get(2), None);
+        assert_eq!(balancer.index_of(target(1, 1).addr), Some(0));
+        assert_eq!(balancer.index_of(target(3, 1).addr), None);
+    }
+
+    #[test]
+    fn test_display() {
+        let route = Route {
+            subdomain: "web".to_string(),
+            targets: vec![target(80, 1), target(81, 3)],
+            socket: None,
+            limits: Limits::default(),
+        };
+        assert_eq!(route.to_string(), "web=127.0.0.1:80,127.0.0.1:81@3");
+
+        let route = Route {
+            limits: Limits {
+                timeout_ms: Some(2000),
+                max_body_bytes: Some(1024),
+                methods: Some(vec![Method::GET, Method::HEAD]),
+            },
+            ..route
+        };
+        assert_eq!(
+            route.to_string(),
+            "web=127.0.0.1:80,127.0.0.1:81@3;timeout=2000;max-body=1024;methods=GET,HEAD"
+        );
+
+        let route = Route {
+            subdomain: "app".to_string(),
+            targets: Vec::new(),
+            socket: Some(PathBuf::from("/run/app.sock")),
+            limits: Limits::default(),
+        };
+        assert_eq!(route.to_string(), "app=unix:/run/app.sock");
+    }
+}
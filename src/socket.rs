@@ -0,0 +1,54 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::{io, time::Duration};
+use tokio::net::TcpStream;
+
+/// `--tcp-nodelay` and `--tcp-keepalive-ms`, applied to accepted client
+/// sockets and backend connections alike
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    /// idle time before the first keepalive probe, also the time between
+    /// probes where that can be set
+    pub keepalive: Option<Duration>,
+}
+
+impl SocketOptions {
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+            let keepalive = keepalive.with_interval(time);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_apply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        SocketOptions::default().apply(&stream).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(30)),
+        };
+        options.apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+}